@@ -1,3 +1,3 @@
 mod settings;
 
-pub use settings::{Settings};
\ No newline at end of file
+pub use settings::{Settings, QueueBackendKind, IngestionMode, MoneyFormat};
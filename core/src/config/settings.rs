@@ -1,21 +1,206 @@
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use config::{Case, Config};
+use config::{Case, Config, ConfigError};
+use shared::{ClientSettings, ProcessorConfig, parse_processors};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackendKind {
+    Redis,
+    Memory,
+    Postgres,
+}
+
+/// How `POST /payments` hands a payment over: `Sync` calls the processor in the request
+/// and only queues on failure, `Async` queues every payment and answers `202 Accepted`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestionMode {
+    Sync,
+    Async,
+}
+
+/// How amounts are written in the API: exact JSON numbers (`10.50`), strings (`"10.50"`),
+/// or integer minor units (`1050`) with `payment_max_scale` decimal places.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MoneyFormat {
+    Number,
+    String,
+    MinorUnits,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub server_url: String,
     pub server_port: u16,
     pub redis_url: String,
+    // Proxy endpoint; without it the processors are called directly
+    #[serde(default)]
     pub payment_processor_url: String,
+    // JSON list of processors, see `shared::ProcessorConfig`; the same list the proxy reads. Through
+    // the proxy only their names are used, for the summary. The default and fallback URLs
+    // below are a shorter way to list the usual two
+    pub payment_processors: Option<String>,
+    pub payment_processor_default: Option<String>,
+    pub payment_processor_fallback: Option<String>,
     pub payment_topic: String,
     pub db_host: String,
     pub db_port: u16,
     pub db_name: String,
     pub db_user: String,
     pub db_password: String,
+    #[serde(default = "default_queue_backend")]
+    pub queue_backend: QueueBackendKind,
+    #[serde(default = "default_consumer_group")]
+    pub consumer_group: String,
+    #[serde(default = "default_consumer_name")]
+    pub consumer_name: String,
+    #[serde(default = "default_claim_min_idle_ms")]
+    pub claim_min_idle_ms: u64,
+    #[serde(default = "default_consumer_concurrency")]
+    pub consumer_concurrency: usize,
+    #[serde(default = "default_dlq_consumer_concurrency")]
+    pub dlq_consumer_concurrency: usize,
+    #[serde(default = "default_shutdown_timeout_ms")]
+    pub shutdown_timeout_ms: u64,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    #[serde(default = "default_retry_multiplier")]
+    pub retry_multiplier: f64,
+    #[serde(default = "default_retry_jitter_ms")]
+    pub retry_jitter_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    #[serde(default = "default_retry_poll_interval_ms")]
+    pub retry_poll_interval_ms: u64,
+    #[serde(default = "default_idempotency_claim_timeout_ms")]
+    pub idempotency_claim_timeout_ms: u64,
+    #[serde(default = "default_ingestion_mode")]
+    pub ingestion_mode: IngestionMode,
+    #[serde(flatten)]
+    pub client: ClientSettings,
+    #[serde(default = "default_processor_health_check_interval_ms")]
+    pub processor_health_check_interval_ms: u64,
+    // Poll processor health from one replica at a time and share it through Redis
+    #[serde(default)]
+    pub processor_health_shared: bool,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub health_check_timeout_ms: u64,
+    // Largest amount `POST /payments` takes, and how many decimal places it may have
+    #[serde(default = "default_payment_max_amount")]
+    pub payment_max_amount: Decimal,
+    #[serde(default = "default_payment_max_scale")]
+    pub payment_max_scale: u32,
+    #[serde(default = "default_money_format")]
+    pub money_format: MoneyFormat,
+    // Bearer token the `/admin` routes require; without one they refuse every request
+    pub admin_token: Option<String>,
+}
+
+fn default_queue_backend() -> QueueBackendKind {
+    QueueBackendKind::Redis
+}
+
+fn default_consumer_group() -> String {
+    "core".to_string()
+}
+
+// Every replica must join the group under its own name, so fall back to the container hostname
+fn default_consumer_name() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| uuid::Uuid::new_v4().to_string())
+}
+
+fn default_claim_min_idle_ms() -> u64 {
+    30_000
+}
+
+fn default_consumer_concurrency() -> usize {
+    4
+}
+
+fn default_dlq_consumer_concurrency() -> usize {
+    1
+}
+
+fn default_shutdown_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_retry_multiplier() -> f64 {
+    2.0
+}
+
+fn default_retry_jitter_ms() -> u64 {
+    250
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_retry_poll_interval_ms() -> u64 {
+    200
+}
+
+// An in-flight claim older than this is assumed abandoned and can be taken over
+fn default_idempotency_claim_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_ingestion_mode() -> IngestionMode {
+    IngestionMode::Sync
+}
+
+// The processors only answer one health check per caller every five seconds
+fn default_processor_health_check_interval_ms() -> u64 {
+    5_000
+}
+
+// A dependency slower than this to answer a readiness check is reported as down
+fn default_health_check_timeout_ms() -> u64 {
+    1_000
+}
+
+fn default_payment_max_amount() -> Decimal {
+    Decimal::from(1_000_000)
+}
+
+// Cents
+fn default_payment_max_scale() -> u32 {
+    2
+}
+
+fn default_money_format() -> MoneyFormat {
+    MoneyFormat::Number
 }
 
 impl Settings {
+    /// Whether core calls the processors itself instead of going through the proxy.
+    pub fn calls_processors_directly(&self) -> bool {
+        self.payment_processor_url.is_empty()
+    }
+
+    /// Every processor a payment can be recorded against, sorted by priority. Their URLs
+    /// are only required when core calls them directly.
+    pub fn processors(&self) -> Result<Vec<ProcessorConfig>, ConfigError> {
+        // Through the proxy these are the names it gives the same two processors
+        let processors = parse_processors(self.payment_processors.as_deref(), || Ok(vec![
+            ProcessorConfig::new("default", self.payment_processor_default.clone().unwrap_or_default(), 0),
+            ProcessorConfig::new("fallback", self.payment_processor_fallback.clone().unwrap_or_default(), 1),
+        ]))?;
+
+        if self.calls_processors_directly() && processors.iter().any(|processor| processor.url.is_empty()) {
+            return Err(ConfigError::Message("Set APP_PAYMENT_PROCESSOR_URL, APP_PAYMENT_PROCESSORS, or both APP_PAYMENT_PROCESSOR_DEFAULT and APP_PAYMENT_PROCESSOR_FALLBACK".to_string()));
+        }
+
+        Ok(processors)
+    }
+
     pub fn new() -> Self {
         let cfg = Config::builder()
             .add_source(config::Environment::with_prefix("APP")
@@ -27,3 +212,44 @@ impl Settings {
         cfg.try_deserialize().unwrap()
     }
 }
+
+#[cfg(test)]
+impl Settings {
+    /// Settings for a local run on the in-memory queue, with the named settings in
+    /// `overrides` (a JSON object) on top.
+    pub fn for_tests(overrides: serde_json::Value) -> Self {
+        let mut settings = serde_json::json!({
+            "server_url": "127.0.0.1",
+            "server_port": 9999,
+            "redis_url": "redis://127.0.0.1",
+            "payment_topic": "payments",
+            "db_host": "localhost",
+            "db_port": 5432,
+            "db_name": "postgres",
+            "db_user": "postgres",
+            "db_password": "postgres",
+            "queue_backend": "memory",
+        });
+        if let (Some(settings), serde_json::Value::Object(overrides)) = (settings.as_object_mut(), overrides) {
+            settings.extend(overrides);
+        }
+
+        serde_json::from_value(settings).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn takes_processors_without_urls_only_through_the_proxy() {
+        let through_proxy = Settings::for_tests(json!({ "payment_processor_url": "http://proxy/payments" }));
+        let direct = Settings::for_tests(json!({ "payment_processor_default": "http://default" }));
+
+        let names = through_proxy.processors().unwrap().into_iter().map(|processor| processor.name).collect::<Vec<_>>();
+        assert_eq!(names, ["default", "fallback"]);
+        assert!(direct.processors().is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use async_trait::async_trait;
-use tracing::{info, error};
+use tracing::{info, error, warn};
+use crate::errors::CoreError;
 use crate::queue::QueueConsumerHandler;
-use crate::models::Payment;
+use crate::models::{Payment, PaymentStatus, QueuedPayment};
 use crate::usecases::UseCases;
 
 #[derive(Clone)]
@@ -15,19 +16,28 @@ impl PaymentConsumer {
             usecases,
         }
     }
+
+    async fn record_status(&self, message: &str, status: PaymentStatus, retry_count: u8, error: &CoreError) {
+        match serde_json::from_str::<QueuedPayment>(message) {
+            Ok(payment) => self.usecases.process_payment
+                .record_status(&payment.correlation_id, status, Some(retry_count), Some(error.to_string()))
+                .await,
+            Err(e) => error!("Failed to parse payment message: {}", e),
+        }
+    }
 }
 
 #[async_trait]
 impl QueueConsumerHandler for PaymentConsumer {
-    async fn consume(&self, message: String) -> Result<(), String> {
+    async fn consume(&self, message: String) -> Result<(), CoreError> {
         info!("Consuming message: {}", message);
 
-        let payment = match serde_json::from_str::<Payment>(message.as_str()) {
-            Ok(p) => p,
+        let payment = match serde_json::from_str::<QueuedPayment>(message.as_str()) {
+            Ok(p) => Payment::from(p),
             Err(e) => {
                 let error_msg = format!("Failed to parse payment message: {}", e);
                 error!("{}", error_msg);
-                return Err(error_msg);
+                return Err(CoreError::Serialization(error_msg));
             }
         };
 
@@ -36,11 +46,27 @@ impl QueueConsumerHandler for PaymentConsumer {
                 info!("Payment processed successfully");
                 Ok(())
             },
+            Err(CoreError::ProcessorPermanent(e)) => {
+                // Already marked failed; retrying would only be rejected again
+                warn!("Payment rejected by processor, settling message: {}", e);
+                Ok(())
+            },
             Err(e) => {
-                let error_msg = format!("Failed to process payment: {}", e);
-                error!("{}", error_msg);
-                Err(error_msg)
+                error!("Failed to process payment: {}", e);
+                Err(e)
             }
         }
     }
+
+    async fn retrying(&self, message: &str, retry_count: u8, error: &CoreError) {
+        self.record_status(message, PaymentStatus::Retrying, retry_count, error).await;
+    }
+
+    async fn dead_lettered(&self, message: &str, retry_count: u8, error: &CoreError) {
+        self.record_status(message, PaymentStatus::DeadLettered, retry_count, error).await;
+    }
+
+    async fn quarantined(&self, message: &str, retry_count: u8, error: &CoreError) {
+        self.record_status(message, PaymentStatus::Failed, retry_count, error).await;
+    }
 }
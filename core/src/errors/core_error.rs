@@ -0,0 +1,92 @@
+use std::fmt::Display;
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use serde_json::json;
+use crate::errors::FieldError;
+
+#[derive(Debug, Clone)]
+pub enum CoreError {
+    // The request or message itself is wrong and will never succeed as it is
+    Validation(String),
+    // Fields of the request that were rejected; the request is not acted on at all
+    InvalidFields(Vec<FieldError>),
+    // The processor could not take the payment right now; trying again may work
+    ProcessorTransient(String),
+    // The processor did not answer in time; like `ProcessorTransient`, but it may still
+    // have taken the payment
+    ProcessorTimeout(String),
+    // The processor refused the payment for good
+    ProcessorPermanent(String),
+    Queue(String),
+    Storage(String),
+    Serialization(String),
+}
+
+impl CoreError {
+    /// Whether handing the same message back for another attempt can change the result.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CoreError::ProcessorTransient(_) | CoreError::ProcessorTimeout(_) | CoreError::Queue(_) | CoreError::Storage(_) => true,
+            CoreError::Validation(_) | CoreError::InvalidFields(_) | CoreError::ProcessorPermanent(_) | CoreError::Serialization(_) => false,
+        }
+    }
+
+    /// Short, stable name of the variant, used as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CoreError::Validation(_) | CoreError::InvalidFields(_) => "validation",
+            CoreError::ProcessorTransient(_) => "transient",
+            CoreError::ProcessorTimeout(_) => "timeout",
+            CoreError::ProcessorPermanent(_) => "rejected",
+            CoreError::Queue(_) => "queue",
+            CoreError::Storage(_) => "storage",
+            CoreError::Serialization(_) => "serialization",
+        }
+    }
+}
+
+impl Display for CoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoreError::Validation(msg) => write!(f, "validation error: {}", msg),
+            CoreError::InvalidFields(fields) => {
+                let fields = fields.iter()
+                    .map(|e| format!("{} {}", e.field, e.message))
+                    .collect::<Vec<_>>();
+                write!(f, "invalid request: {}", fields.join(", "))
+            },
+            CoreError::ProcessorTransient(msg) => write!(f, "processor unavailable: {}", msg),
+            CoreError::ProcessorTimeout(msg) => write!(f, "processor timed out: {}", msg),
+            CoreError::ProcessorPermanent(msg) => write!(f, "processor rejected payment: {}", msg),
+            CoreError::Queue(msg) => write!(f, "queue error: {}", msg),
+            CoreError::Storage(msg) => write!(f, "storage error: {}", msg),
+            CoreError::Serialization(msg) => write!(f, "serialization error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CoreError {}
+
+impl ResponseError for CoreError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CoreError::Validation(_) => StatusCode::BAD_REQUEST,
+            // A value that could not be read is a bad request, one that breaks a rule is not processable
+            CoreError::InvalidFields(fields) if fields.iter().any(|e| e.malformed) => StatusCode::BAD_REQUEST,
+            CoreError::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            CoreError::ProcessorTransient(_) => StatusCode::BAD_GATEWAY,
+            CoreError::ProcessorTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            CoreError::ProcessorPermanent(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            CoreError::Queue(_) | CoreError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+            CoreError::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let CoreError::InvalidFields(fields) = self {
+            return HttpResponse::build(self.status_code()).json(json!({ "error": "invalid request", "fields": fields }));
+        }
+
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
@@ -0,0 +1,21 @@
+use serde::Serialize;
+
+/// One rejected field of a request, as listed in the error body.
+#[derive(Debug, Serialize, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+    // The value could not be read at all, as opposed to breaking a rule once read
+    #[serde(skip)]
+    pub malformed: bool,
+}
+
+impl FieldError {
+    pub fn malformed(field: &str, message: String) -> Self {
+        Self { field: field.to_string(), message, malformed: true }
+    }
+
+    pub fn invalid(field: &str, message: String) -> Self {
+        Self { field: field.to_string(), message, malformed: false }
+    }
+}
@@ -0,0 +1,5 @@
+mod core_error;
+mod field_error;
+
+pub use core_error::{CoreError};
+pub use field_error::{FieldError};
@@ -1,6 +1,8 @@
 extern crate core;
 
 mod config;
+mod errors;
+mod metrics;
 mod queue;
 mod routes;
 mod usecases;
@@ -9,17 +11,22 @@ mod outbound;
 mod consumers;
 mod store;
 mod serializers;
+mod shutdown;
 
 use actix_web::{web, App, HttpServer};
+use actix_web::dev::Service;
 use std::io::{Result};
+use std::time::{Duration, Instant};
 use actix_web::middleware::Logger;
-use tracing::{info};
+use tracing::{info, warn};
 use tracing_subscriber::{fmt};
 
 use config::{Settings};
-use crate::queue::{Producer, Consumer, DLQConsumer};
+use crate::metrics::Metrics;
+use crate::queue::{Consumer, DLQConsumer};
 use crate::outbound::PaymentProcessor;
 use crate::usecases::UseCases;
+use crate::shutdown::ShutdownCoordinator;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -27,29 +34,84 @@ async fn main() -> Result<()> {
     info!("Starting anibalmf1-rust server");
 
     let settings = Settings::new();
+    serializers::decimal::configure(settings.money_format, settings.payment_max_scale);
+    let metrics = Metrics::new();
 
-    let producer = Producer::new(settings.clone()).await;
-    let consumer = Consumer::new(settings.clone()).await;
-    let payment_processor = PaymentProcessor::new(settings.payment_processor_url.clone()).await;
-    let payment_store = store::PaymentStore::new(settings.clone()).await;
-    let usecases = UseCases::new(producer, payment_processor, payment_store).await;
+    let db_pool = store::create_pool(&settings).await;
+    let queue_backend = queue::connect(&settings, db_pool.clone()).await;
+
+    let consumer = Consumer::new(queue_backend.clone(), settings.clone(), metrics.clone()).await;
+    let processors = settings.processors().map_err(std::io::Error::other)?;
+    let client = shared::build_client(&settings.client).map_err(std::io::Error::other)?;
+    let payment_processor = PaymentProcessor::new(&settings, processors, client, metrics.clone()).await;
+    let payment_store = store::PaymentStore::new(db_pool.clone(), metrics.clone()).await;
+    let idempotency_store = store::IdempotencyStore::new(db_pool.clone(), &settings).await;
+    let shutdown_timeout = Duration::from_millis(settings.shutdown_timeout_ms);
+    let mut shutdown = ShutdownCoordinator::new(shutdown_timeout);
+    let usecases = UseCases::new(payment_processor, payment_store, idempotency_store, queue_backend.clone(), metrics.clone(), shutdown.status(), &settings).await;
     let payment_consumer = consumers::PaymentConsumer::new(usecases.clone()).await;
-    let dlq_consumer = DLQConsumer::new(settings.clone()).await;
+    let dlq_consumer = DLQConsumer::new(queue_backend.clone(), settings.clone(), metrics.clone()).await;
 
     // Start consuming messages from the queue
-    consumer.start_consuming(payment_consumer.clone()).await;
-    dlq_consumer.start_consuming(payment_consumer).await;
+    shutdown.track("payment consumer", consumer.start_consuming(payment_consumer.clone(), shutdown.signal()).await);
+    shutdown.track("DLQ consumer", dlq_consumer.start_consuming(payment_consumer, shutdown.signal()).await);
+
+    let admin_token = routes::AdminToken::new(settings.admin_token.clone());
+    if !admin_token.is_configured() {
+        warn!("No admin token configured, the DLQ routes refuse every request");
+    }
 
+    // Resolves once a stop signal was received and in-flight HTTP requests have finished,
+    // so nothing new can be accepted while the consumers drain below
     HttpServer::new(move || {
+        let metrics = metrics.clone();
+
         App::new()
+            // Labelled by route pattern so every correlation id does not become its own series
+            .wrap_fn(move |req, srv| {
+                let metrics = metrics.clone();
+                let started = Instant::now();
+                let method = req.method().to_string();
+                let response = srv.call(req);
+
+                async move {
+                    let res = response.await?;
+                    let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                    metrics.observe_request(&route, &method, res.status().as_u16(), started.elapsed());
+                    Ok(res)
+                }
+            })
             .wrap(Logger::default())
             .app_data(web::Data::new(usecases.clone()))
+            .app_data(web::Data::new(admin_token.clone()))
+            .app_data(web::JsonConfig::default().error_handler(routes::json_error))
             .service(routes::process_payment)
+            .service(routes::get_payment)
             .service(routes::get_summary)
+            .service(routes::get_metrics)
+            .service(routes::live)
+            .service(routes::ready)
+            .service(routes::list_dead_letters)
+            .service(routes::get_dead_letter)
+            .service(routes::replay_dead_letters)
+            .service(routes::replay_dead_letter)
+            .service(routes::quarantine_dead_letters)
+            .service(routes::quarantine_dead_letter)
+            .service(routes::purge_dead_letters)
     })
+        .shutdown_timeout(shutdown_timeout.as_secs().max(1))
         .bind((settings.server_url.clone(), settings.server_port))?
         .run()
-        .await
+        .await?;
+
+    info!("HTTP server stopped");
+    shutdown.drain().await;
+
+    queue_backend.close().await;
+    db_pool.close();
+    info!("Shutdown complete");
+
+    Ok(())
 }
 
 fn init_tracing() {
@@ -0,0 +1,171 @@
+use std::time::Duration;
+use deadpool_postgres::Status;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use crate::errors::CoreError;
+use crate::queue::QueueKind;
+
+fn queue_label(queue: QueueKind) -> &'static str {
+    match queue {
+        QueueKind::Main => "main",
+        QueueKind::DeadLetter => "dead_letter",
+        QueueKind::Quarantine => "quarantine",
+    }
+}
+
+/// Everything core reports on `/metrics`. Clones share the same collectors.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    payments: IntCounterVec,
+    processor_calls: IntCounterVec,
+    processor_call_duration: HistogramVec,
+    processor_failing: IntGaugeVec,
+    queue_depth: IntGaugeVec,
+    queue_retries: IntCounter,
+    queue_dead_lettered: IntCounter,
+    consumer_in_flight: IntGaugeVec,
+    db_pool_connections: IntGaugeVec,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// Counts a message as in flight until dropped, so aborted workers are let go of too.
+pub struct InFlightGuard(IntGauge);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("core".to_string()), None).unwrap();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served, by route, method and status"),
+            &["route", "method", "status"],
+        ).unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time spent serving HTTP requests, by route and method"),
+            &["route", "method"],
+        ).unwrap();
+        let payments = IntCounterVec::new(
+            Opts::new("payments_total", "Payments taken in, by how they were settled"),
+            &["outcome"],
+        ).unwrap();
+        let processor_calls = IntCounterVec::new(
+            Opts::new("processor_calls_total", "Calls to the payment processors, by processor and outcome"),
+            &["processor", "outcome"],
+        ).unwrap();
+        let processor_call_duration = HistogramVec::new(
+            HistogramOpts::new("processor_call_duration_seconds", "Time spent calling the payment processors, by processor"),
+            &["processor"],
+        ).unwrap();
+        let processor_failing = IntGaugeVec::new(
+            Opts::new("processor_failing", "1 while a processor called directly is considered failing"),
+            &["processor"],
+        ).unwrap();
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("queue_depth", "Messages waiting in each queue"),
+            &["queue"],
+        ).unwrap();
+        let queue_retries = IntCounter::new("queue_retries_total", "Messages handed back to the queue for another attempt").unwrap();
+        let queue_dead_lettered = IntCounter::new("queue_dead_lettered_total", "Messages moved to the dead letter queue").unwrap();
+        let consumer_in_flight = IntGaugeVec::new(
+            Opts::new("consumer_in_flight", "Messages being handled right now, by queue"),
+            &["queue"],
+        ).unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Postgres pool connections: max, open, idle, and callers waiting for one"),
+            &["state"],
+        ).unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_request_duration.clone())).unwrap();
+        registry.register(Box::new(payments.clone())).unwrap();
+        registry.register(Box::new(processor_calls.clone())).unwrap();
+        registry.register(Box::new(processor_call_duration.clone())).unwrap();
+        registry.register(Box::new(processor_failing.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(queue_retries.clone())).unwrap();
+        registry.register(Box::new(queue_dead_lettered.clone())).unwrap();
+        registry.register(Box::new(consumer_in_flight.clone())).unwrap();
+        registry.register(Box::new(db_pool_connections.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            payments,
+            processor_calls,
+            processor_call_duration,
+            processor_failing,
+            queue_depth,
+            queue_retries,
+            queue_dead_lettered,
+            consumer_in_flight,
+            db_pool_connections,
+        }
+    }
+
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        self.http_requests.with_label_values(&[route, method, &status.to_string()]).inc();
+        self.http_request_duration.with_label_values(&[route, method]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_payment(&self, outcome: &str) {
+        self.payments.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn observe_processor_call(&self, processor: &str, outcome: &str, elapsed: Duration) {
+        self.processor_calls.with_label_values(&[processor, outcome]).inc();
+        self.processor_call_duration.with_label_values(&[processor]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn set_processor_failing(&self, processor: &str, failing: bool) {
+        self.processor_failing.with_label_values(&[processor]).set(failing as i64);
+    }
+
+    pub fn set_queue_depth(&self, queue: QueueKind, depth: usize) {
+        self.queue_depth.with_label_values(&[queue_label(queue)]).set(depth as i64);
+    }
+
+    pub fn inc_retries(&self) {
+        self.queue_retries.inc();
+    }
+
+    pub fn inc_dead_lettered(&self) {
+        self.queue_dead_lettered.inc();
+    }
+
+    pub fn track_in_flight(&self, queue: QueueKind) -> InFlightGuard {
+        let gauge = self.consumer_in_flight.with_label_values(&[queue_label(queue)]);
+        gauge.inc();
+        InFlightGuard(gauge)
+    }
+
+    pub fn set_db_pool(&self, status: Status) {
+        self.db_pool_connections.with_label_values(&["max"]).set(status.max_size as i64);
+        self.db_pool_connections.with_label_values(&["open"]).set(status.size as i64);
+        self.db_pool_connections.with_label_values(&["idle"]).set(status.available as i64);
+        self.db_pool_connections.with_label_values(&["waiting"]).set(status.waiting as i64);
+    }
+
+    /// Renders every collector in the Prometheus text format.
+    pub fn encode(&self) -> Result<String, CoreError> {
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| CoreError::Serialization(format!("Failed to encode metrics: {}", e)))?;
+
+        String::from_utf8(buffer).map_err(|e| CoreError::Serialization(format!("Failed to encode metrics: {}", e)))
+    }
+}
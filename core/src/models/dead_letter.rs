@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetterEntry {
+    pub id: String,
+    #[serde(rename = "retryCount")]
+    pub retry_count: u8,
+    #[serde(rename = "firstFailedAt")]
+    pub first_failed_at: Option<String>,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    // The queued payment as published, or the raw message when it is not valid JSON
+    pub message: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetterPage {
+    pub entries: Vec<DeadLetterEntry>,
+    // Pass as `after` to fetch the next page; absent on the last page
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+    pub total: usize,
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Up,
+    Down,
+    // Not used with the current settings, so it does not count against readiness
+    Disabled,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DependencyHealth {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DependencyHealth {
+    pub fn up() -> Self {
+        Self { status: CheckStatus::Up, error: None }
+    }
+
+    pub fn down(error: String) -> Self {
+        Self { status: CheckStatus::Down, error: Some(error) }
+    }
+
+    pub fn disabled() -> Self {
+        Self { status: CheckStatus::Disabled, error: None }
+    }
+}
+
+/// Answer of `GET /health/ready`: down as soon as one dependency in use is down.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Readiness {
+    pub status: CheckStatus,
+    pub checks: BTreeMap<String, DependencyHealth>,
+}
+
+impl Readiness {
+    pub fn new(checks: BTreeMap<String, DependencyHealth>) -> Self {
+        let status = if checks.values().any(|check| check.status == CheckStatus::Down) {
+            CheckStatus::Down
+        } else {
+            CheckStatus::Up
+        };
+
+        Self { status, checks }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.status == CheckStatus::Up
+    }
+}
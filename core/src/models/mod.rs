@@ -1,3 +1,7 @@
+mod dead_letter;
+mod health;
 mod payment;
 
-pub use payment::{Payment, PaymentSummary, PaymentMetric, PaymentProcessorName};
+pub use dead_letter::{DeadLetterEntry, DeadLetterPage};
+pub use health::{DependencyHealth, Readiness};
+pub use payment::{Payment, PaymentDetails, PaymentSummary, PaymentMetric, PaymentOutcome, PaymentStatus, QueuedPayment, SummaryWindow};
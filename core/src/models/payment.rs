@@ -1,17 +1,99 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use rust_decimal::Decimal;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::errors::{CoreError, FieldError};
 
-pub enum PaymentProcessorName {
-    Default,
-    Fallback,
+/// Where a payment is in its lifecycle. Only `Succeeded` payments count towards the summary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    Received,
+    Processing,
+    Succeeded,
+    Retrying,
+    DeadLettered,
+    Failed,
 }
 
-impl Display for PaymentProcessorName {
+impl PaymentStatus {
+    /// Statuses a payment can move to this one from. A payment waiting in the DLQ stays
+    /// dead lettered while the DLQ consumer tries it again, and a succeeded one never moves.
+    pub fn allowed_from(&self) -> &'static [PaymentStatus] {
+        use PaymentStatus::*;
+
+        match self {
+            Received => &[Failed],
+            Processing => &[Received, Retrying],
+            Succeeded => &[Received, Processing, Retrying, DeadLettered, Failed],
+            Retrying => &[Received, Processing, DeadLettered],
+            DeadLettered => &[Received, Processing, Retrying],
+            Failed => &[Received, Processing, Retrying, DeadLettered],
+        }
+    }
+
+    pub fn from_str(status: &str) -> Option<Self> {
+        match status {
+            "received" => Some(PaymentStatus::Received),
+            "processing" => Some(PaymentStatus::Processing),
+            "succeeded" => Some(PaymentStatus::Succeeded),
+            "retrying" => Some(PaymentStatus::Retrying),
+            "dead_lettered" => Some(PaymentStatus::DeadLettered),
+            "failed" => Some(PaymentStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PaymentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            PaymentStatus::Received => "received",
+            PaymentStatus::Processing => "processing",
+            PaymentStatus::Succeeded => "succeeded",
+            PaymentStatus::Retrying => "retrying",
+            PaymentStatus::DeadLettered => "dead_lettered",
+            PaymentStatus::Failed => "failed",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// What `POST /payments` did with a correlation id, remembered so a repeated request
+/// gets the same answer instead of being processed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaymentOutcome {
+    // Charged by a processor and recorded
+    Processed,
+    // The processor call failed and the payment was handed to the queue
+    Queued,
+    // Queued without calling the processor, see `IngestionMode::Async`
+    Accepted,
+    // Another request with the same correlation id has not finished yet
+    InFlight,
+}
+
+impl PaymentOutcome {
+    pub fn from_str(outcome: &str) -> Option<Self> {
+        match outcome {
+            "processed" => Some(PaymentOutcome::Processed),
+            "queued" => Some(PaymentOutcome::Queued),
+            "accepted" => Some(PaymentOutcome::Accepted),
+            "in_flight" => Some(PaymentOutcome::InFlight),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PaymentOutcome {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
-            PaymentProcessorName::Default => "default".to_string(),
-            PaymentProcessorName::Fallback => "fallback".to_string(),
+            PaymentOutcome::Processed => "processed",
+            PaymentOutcome::Queued => "queued",
+            PaymentOutcome::Accepted => "accepted",
+            PaymentOutcome::InFlight => "in_flight",
         };
         write!(f, "{}", str)
     }
@@ -21,12 +103,75 @@ impl Display for PaymentProcessorName {
 pub struct Payment {
     #[serde(rename = "correlationId")]
     pub correlation_id: String,
+    #[serde(with = "crate::serializers::decimal")]
+    pub amount: Decimal,
+    #[serde(rename = "requestedAt")]
+    #[serde(default)]
+    pub requested_at: String,
+}
+
+impl Payment {
+    /// Checks what `POST /payments` was given, listing every field that is wrong.
+    pub fn validate(&self, max_amount: Decimal, max_scale: u32) -> Result<(), CoreError> {
+        let mut errors = Vec::new();
+
+        if Uuid::parse_str(&self.correlation_id).is_err() {
+            errors.push(FieldError::malformed("correlationId", "must be a UUID".to_string()));
+        }
+
+        if self.amount <= Decimal::ZERO {
+            errors.push(FieldError::invalid("amount", "must be greater than 0".to_string()));
+        } else if self.amount > max_amount {
+            errors.push(FieldError::invalid("amount", format!("must be at most {}", max_amount)));
+        }
+
+        // Trailing zeros do not count, 10.50 is as good as 10.5
+        if self.amount.normalize().scale() > max_scale {
+            errors.push(FieldError::invalid("amount", format!("must have at most {} decimal places", max_scale)));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(CoreError::InvalidFields(errors))
+        }
+    }
+}
+
+/// A payment as it travels through the queue. The amount is always an exact JSON number,
+/// whatever `APP_MONEY_FORMAT` the API uses, so queued and dead lettered messages stay
+/// readable when the format changes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueuedPayment {
+    #[serde(rename = "correlationId")]
+    pub correlation_id: String,
+    #[serde(with = "rust_decimal::serde::arbitrary_precision")]
     pub amount: Decimal,
     #[serde(rename = "requestedAt")]
     #[serde(default)]
     pub requested_at: String,
 }
 
+impl From<&Payment> for QueuedPayment {
+    fn from(payment: &Payment) -> Self {
+        Self {
+            correlation_id: payment.correlation_id.clone(),
+            amount: payment.amount,
+            requested_at: payment.requested_at.clone(),
+        }
+    }
+}
+
+impl From<QueuedPayment> for Payment {
+    fn from(payment: QueuedPayment) -> Self {
+        Self {
+            correlation_id: payment.correlation_id,
+            amount: payment.amount,
+            requested_at: payment.requested_at,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PaymentMetric {
     #[serde(rename = "totalRequests")]
@@ -36,8 +181,187 @@ pub struct PaymentMetric {
     pub total_amount: Decimal,
 }
 
+impl PaymentMetric {
+    pub fn empty() -> Self {
+        Self { total_requests: 0, total_amount: Decimal::ZERO }
+    }
+}
+
+/// Totals per processor name, serialized as one field per processor, next to the window
+/// they were taken over.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PaymentSummary {
-    pub default: PaymentMetric,
-    pub fallback: PaymentMetric,
+    #[serde(flatten)]
+    pub processors: BTreeMap<String, PaymentMetric>,
+    pub window: SummaryWindow,
+}
+
+/// Inclusive range of `requestedAt` the summary covers; an unset bound leaves that side open.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct SummaryWindow {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Timestamps without an offset are taken as UTC
+const NAIVE_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+
+// Reads an RFC 3339 timestamp, a timestamp without offset, or a date alone. A date alone
+// stands for its first instant, or its last one when it closes the window.
+fn parse_bound(value: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Some(at.with_timezone(&Utc));
+    }
+
+    if let Some(at) = NAIVE_FORMATS.iter().find_map(|format| NaiveDateTime::parse_from_str(value, format).ok()) {
+        return Some(at.and_utc());
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let time = if end_of_day {
+        NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999)?
+    } else {
+        NaiveTime::MIN
+    };
+    Some(date.and_time(time).and_utc())
+}
+
+impl SummaryWindow {
+    /// Parses the `from` and `to` query parameters, rejecting any that cannot be read and
+    /// a window that ends before it starts.
+    pub fn parse(from: Option<&str>, to: Option<&str>) -> Result<Self, CoreError> {
+        let mut errors = Vec::new();
+        let expected = "must be an RFC 3339 timestamp, a timestamp without offset, or a date";
+
+        let mut bound = |field: &str, value: Option<&str>, end_of_day: bool| {
+            let value = value?;
+            let at = parse_bound(value, end_of_day);
+            if at.is_none() {
+                errors.push(FieldError::malformed(field, format!("{}, got '{}'", expected, value)));
+            }
+            at
+        };
+
+        let window = Self {
+            from: bound("from", from, false),
+            to: bound("to", to, true),
+        };
+
+        // An empty window is as unusable as an unreadable date
+        if let (Some(from), Some(to)) = (window.from, window.to)
+            && from > to
+        {
+            errors.push(FieldError::malformed("from", "must not be after to".to_string()));
+        }
+
+        if errors.is_empty() {
+            Ok(window)
+        } else {
+            Err(CoreError::InvalidFields(errors))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PaymentDetails {
+    #[serde(rename = "correlationId")]
+    pub correlation_id: String,
+    #[serde(serialize_with = "crate::serializers::decimal::serialize")]
+    pub amount: Decimal,
+    // Set once a processor accepted the payment
+    #[serde(rename = "paymentProcessor")]
+    pub payment_processor: Option<String>,
+    #[serde(rename = "requestedAt")]
+    pub requested_at: String,
+    pub status: PaymentStatus,
+    #[serde(rename = "retryCount")]
+    pub retry_count: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_transitions() {
+        use PaymentStatus::*;
+
+        let statuses = [Received, Processing, Succeeded, Retrying, DeadLettered, Failed];
+        // Whether the row status may move to the column status
+        let allowed = [
+            //               received processing succeeded retrying dead_lettered failed
+            /* received      */ [false, true, true, true, true, true],
+            /* processing    */ [false, false, true, true, true, true],
+            /* succeeded     */ [false, false, false, false, false, false],
+            /* retrying      */ [false, true, true, false, true, true],
+            /* dead_lettered */ [false, false, true, true, false, true],
+            /* failed        */ [true, false, true, false, false, false],
+        ];
+
+        for (from, row) in statuses.iter().zip(allowed) {
+            for (to, expected) in statuses.iter().zip(row) {
+                assert_eq!(to.allowed_from().contains(from), expected, "{} -> {}", from, to);
+            }
+        }
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+    }
+
+    // Fields the window was rejected for
+    fn rejected(from: Option<&str>, to: Option<&str>) -> Vec<String> {
+        match SummaryWindow::parse(from, to) {
+            Err(CoreError::InvalidFields(errors)) => {
+                assert!(errors.iter().all(|e| e.malformed));
+                errors.into_iter().map(|e| e.field).collect()
+            },
+            other => panic!("expected the window to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn leaves_missing_bounds_open() {
+        let window = SummaryWindow::parse(None, None).unwrap();
+
+        assert_eq!(window.from, None);
+        assert_eq!(window.to, None);
+    }
+
+    #[test]
+    fn reads_rfc_3339_timestamps_in_any_offset() {
+        let window = SummaryWindow::parse(Some("2025-07-15T12:34:56.000-03:00"), Some("2025-07-15T18:00:00Z")).unwrap();
+
+        assert_eq!(window.from, Some(at("2025-07-15T15:34:56Z")));
+        assert_eq!(window.to, Some(at("2025-07-15T18:00:00Z")));
+    }
+
+    #[test]
+    fn reads_timestamps_without_offset_as_utc() {
+        let window = SummaryWindow::parse(Some("2025-07-15T12:34:56"), Some("2025-07-15 18:00:00.250")).unwrap();
+
+        assert_eq!(window.from, Some(at("2025-07-15T12:34:56Z")));
+        assert_eq!(window.to, Some(at("2025-07-15T18:00:00.250Z")));
+    }
+
+    #[test]
+    fn reads_a_date_as_the_whole_day() {
+        let window = SummaryWindow::parse(Some("2025-07-15"), Some("2025-07-15")).unwrap();
+
+        assert_eq!(window.from, Some(at("2025-07-15T00:00:00Z")));
+        assert_eq!(window.to, Some(at("2025-07-15T23:59:59.999999Z")));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_read() {
+        assert_eq!(rejected(Some("yesterday"), None), ["from"]);
+        assert_eq!(rejected(None, Some("2025-13-01")), ["to"]);
+        assert_eq!(rejected(Some("15/07/2025"), Some("1752582896")), ["from", "to"]);
+    }
+
+    #[test]
+    fn rejects_a_window_that_ends_before_it_starts() {
+        assert_eq!(rejected(Some("2025-07-15T12:00:00Z"), Some("2025-07-15T11:59:59Z")), ["from"]);
+        assert_eq!(rejected(Some("2025-07-16"), Some("2025-07-15")), ["from"]);
+    }
 }
@@ -1,3 +1,4 @@
 mod payment_processor;
+mod router;
 
 pub use payment_processor::{PaymentProcessor};
\ No newline at end of file
@@ -1,46 +1,248 @@
-use reqwest::Client;
-use tracing::error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use reqwest::{Client, StatusCode};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tracing::{info, error, warn};
+use shared::{HealthMonitor, ProcessorConfig};
+use crate::config::Settings;
+use crate::errors::CoreError;
+use crate::metrics::Metrics;
 use crate::models::Payment;
+use crate::outbound::router::{ProcessorRouter, Upstream};
 
 #[derive(Clone, Debug)]
 pub struct PaymentProcessor {
     client: Client,
     processor_url: String,
+    // Set when core calls the processors itself instead of going through the proxy
+    router: Option<ProcessorRouter>,
+    processor_names: Vec<String>,
+    metrics: Metrics,
+}
+
+// What the processors take, whatever money format the API was configured with
+#[derive(Serialize)]
+struct ProcessorRequest<'a> {
+    #[serde(rename = "correlationId")]
+    correlation_id: &'a str,
+    #[serde(serialize_with = "rust_decimal::serde::arbitrary_precision::serialize")]
+    amount: Decimal,
+    #[serde(rename = "requestedAt")]
+    requested_at: &'a str,
+}
+
+// Statuses the processor answers with for a request that may go through when sent again
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Statuses saying no answer came in time, like the proxy's 504 when a processor timed out;
+// the payment may still have gone through
+fn is_timeout_status(status: StatusCode) -> bool {
+    status == StatusCode::GATEWAY_TIMEOUT || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// Statuses the processor uses when the correlation id was already taken
+fn is_duplicate_status(status: StatusCode) -> bool {
+    status == StatusCode::CONFLICT || status == StatusCode::UNPROCESSABLE_ENTITY
+}
+
+fn classify_send_error(e: reqwest::Error) -> CoreError {
+    if e.is_builder() {
+        // The request could not even be built, sending it again changes nothing
+        CoreError::ProcessorPermanent(e.to_string())
+    } else if e.is_timeout() {
+        CoreError::ProcessorTimeout(e.to_string())
+    } else if e.is_connect() {
+        CoreError::ProcessorTransient(format!("connection failed: {}", e))
+    } else {
+        CoreError::ProcessorTransient(e.to_string())
+    }
+}
+
+fn processor_name(res: &reqwest::Response) -> String {
+    res.headers()
+        .get("x-payment-processor")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("default")
+        .to_string()
 }
 
 impl PaymentProcessor {
-    pub async fn new(processor_url: String) -> Self {
+    pub async fn new(settings: &Settings, processors: Vec<ProcessorConfig>, client: Client, metrics: Metrics) -> Self {
+        let processor_names = processors.iter().map(|processor| processor.name.clone()).collect();
+
+        let router = if settings.calls_processors_directly() {
+            for processor in &processors {
+                info!("Calling {} payment processor directly at {}", processor.name, processor.url);
+            }
+            let health_check_interval = Duration::from_millis(settings.processor_health_check_interval_ms);
+            let monitor = HealthMonitor::new(
+                settings.processor_health_shared.then_some(settings.redis_url.as_str()),
+                settings.consumer_name.clone(),
+                health_check_interval,
+                settings.client.request_timeout(),
+                processors.len(),
+            );
+            let router = ProcessorRouter::new(processors, health_check_interval);
+            router.start_health_checks(client.clone(), monitor);
+            Some(router)
+        } else {
+            None
+        };
+
         Self {
-            client: Client::new(),
-            processor_url,
+            client,
+            processor_url: settings.payment_processor_url.clone(),
+            router,
+            processor_names,
+            metrics,
         }
     }
 
-    pub async fn process(self, payment: Payment) -> Result<String, String> {
-        match self.client.post(&self.processor_url)
-            .json(&payment)
+    /// Names of every configured processor, whether or not a payment went through it yet.
+    pub fn processor_names(&self) -> Vec<String> {
+        self.processor_names.clone()
+    }
+
+    /// Returns the name of the processor that accepted the payment. Failures come back as
+    /// `ProcessorTransient` or `ProcessorTimeout` when sending the payment again may work,
+    /// `ProcessorPermanent` otherwise.
+    pub async fn process(self, payment: Payment) -> Result<String, CoreError> {
+        let upstream = self.router.as_ref().map(|router| router.pick().clone());
+        let started = Instant::now();
+
+        let result = self.call(&upstream, payment).await;
+
+        // Through the proxy, a failed call cannot be pinned on one processor
+        let (processor, outcome) = match (&result, &upstream) {
+            (Ok(payment_processor), _) => (payment_processor.as_str(), "success"),
+            (Err(e), Some(upstream)) => (upstream.name.as_str(), e.kind()),
+            (Err(e), None) => ("proxy", e.kind()),
+        };
+        self.metrics.observe_processor_call(processor, outcome, started.elapsed());
+
+        result
+    }
+
+    /// Name and failing flag of every processor core calls directly; empty through the proxy.
+    pub fn processor_health(&self) -> Vec<(String, bool)> {
+        self.router.as_ref().map(ProcessorRouter::health).unwrap_or_default()
+    }
+
+    async fn call(&self, upstream: &Option<Arc<Upstream>>, payment: Payment) -> Result<String, CoreError> {
+        let url = match upstream {
+            Some(upstream) => upstream.payments_url(),
+            None => self.processor_url.clone(),
+        };
+
+        let request = ProcessorRequest {
+            correlation_id: &payment.correlation_id,
+            amount: payment.amount,
+            requested_at: &payment.requested_at,
+        };
+
+        let res = match self.client.post(&url)
+            .json(&request)
             .send()
             .await {
-            Ok(res) => {
-                let status = res.status();
-                // Extract the payment processor header
-                let payment_processor = res.headers()
-                    .get("x-payment-processor")
-                    .and_then(|h| h.to_str().ok())
-                    .unwrap_or("default")
-                    .to_string();
-
-                if status.is_success() {
-                    Ok(payment_processor)
-                } else {
-                    let error = res.text().await.unwrap();
-                    error!("Error processing payment on payment_processor: {} - status: {}", error, status.as_str());
-                    Err(error)
+            Ok(res) => res,
+            Err(e) => {
+                error!("Error processing payment on payment_processor: {}", e);
+                let e = classify_send_error(e);
+                if e.is_retryable() {
+                    self.mark_failing(upstream);
+                }
+                return Err(e);
+            }
+        };
+
+        let status = res.status();
+        // Through the proxy, the header tells which processor was used
+        let payment_processor = match upstream {
+            Some(upstream) => upstream.name.clone(),
+            None => {
+                let name = processor_name(&res);
+                if !self.processor_names.contains(&name) {
+                    warn!("The proxy answered through {} processor, which APP_PAYMENT_PROCESSORS does not list", name);
                 }
+                name
             },
+        };
+
+        if status.is_success() {
+            return Ok(payment_processor);
+        }
+
+        // Through the proxy, the processor that turned the payment down is the one to ask
+        let answered_by = match upstream {
+            Some(_) => None,
+            None => res.headers().get("x-payment-processor").and_then(|h| h.to_str().ok()).map(str::to_string),
+        };
+
+        let error = res.text().await.unwrap_or_default();
+        error!("Error processing payment on payment_processor: {} - status: {}", error, status.as_str());
+        let error = format!("status {}: {}", status.as_str(), error);
+
+        if is_retryable_status(status) {
+            if status.is_server_error() {
+                self.mark_failing(upstream);
+            }
+            if is_timeout_status(status) {
+                return Err(CoreError::ProcessorTimeout(error));
+            }
+            return Err(CoreError::ProcessorTransient(error));
+        }
+
+        // An earlier attempt may have gone through without us seeing the answer
+        if is_duplicate_status(status)
+            && let Some(payment_processor) = self.find_accepted(&payment.correlation_id, answered_by.as_deref()).await?
+        {
+            info!("Payment {} was already accepted by {}", payment.correlation_id, payment_processor);
+            return Ok(payment_processor);
+        }
+
+        Err(CoreError::ProcessorPermanent(error))
+    }
+
+    fn mark_failing(&self, upstream: &Option<Arc<Upstream>>) {
+        if let (Some(router), Some(upstream)) = (&self.router, upstream) {
+            router.mark_failing(upstream);
+        }
+    }
+
+    // Asks the processors whether one of them holds the payment, and which one does. Through
+    // the proxy, the lookup is pinned to `answered_by`, since another processor would not
+    // know the payment. A lookup that cannot tell leaves the payment's fate unknown, which
+    // is worth another attempt rather than failing it
+    async fn find_accepted(&self, correlation_id: &str, answered_by: Option<&str>) -> Result<Option<String>, CoreError> {
+        let Some(router) = &self.router else {
+            let mut request = self.client.get(format!("{}/{}", self.processor_url, correlation_id));
+            if let Some(name) = answered_by {
+                request = request.header("x-payment-processor", name);
+            }
+            return Ok(self.lookup(request, correlation_id).await?.map(|res| processor_name(&res)));
+        };
+
+        for upstream in router.upstreams() {
+            let request = self.client.get(format!("{}/{}", upstream.payments_url(), correlation_id));
+            if self.lookup(request, correlation_id).await?.is_some() {
+                return Ok(Some(upstream.name.clone()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn lookup(&self, request: reqwest::RequestBuilder, correlation_id: &str) -> Result<Option<reqwest::Response>, CoreError> {
+        match request.send().await {
+            Ok(res) if res.status().is_success() => Ok(Some(res)),
+            Ok(res) if res.status() == StatusCode::NOT_FOUND => Ok(None),
+            Ok(res) => Err(CoreError::ProcessorTransient(format!("looking up payment {} answered {}", correlation_id, res.status()))),
             Err(e) => {
-                error!("Error processing payment on payment_processor: {}", e);
-                Err(e.to_string())
+                error!("Failed to look up payment {} on payment_processor: {}", correlation_id, e);
+                Err(classify_send_error(e))
             }
         }
     }
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use reqwest::Client;
+use shared::{HealthMonitor, MonitoredProcessor, ProcessorConfig, ProcessorHealth};
+use tracing::{info, warn};
+
+/// One processor core talks to directly, with the last health it reported.
+#[derive(Debug)]
+pub struct Upstream {
+    pub name: String,
+    pub base_url: String,
+    health: ProcessorHealth,
+    // Set when a failed payment marked the processor as failing, until the other replicas
+    // have been told
+    tripped: AtomicBool,
+    // How long the other replicas are told to treat it as failing: until their next check
+    trip_for: Duration,
+}
+
+impl Upstream {
+    fn new(config: ProcessorConfig, trip_for: Duration) -> Self {
+        Self {
+            health: ProcessorHealth::new(config.name.clone(), format!("{}{}", config.url, config.health_path)),
+            name: config.name,
+            base_url: config.url,
+            tripped: AtomicBool::new(false),
+            trip_for,
+        }
+    }
+
+    pub fn payments_url(&self) -> String {
+        format!("{}/payments", self.base_url)
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.health.is_failing()
+    }
+}
+
+impl MonitoredProcessor for Upstream {
+    fn health(&self) -> &ProcessorHealth {
+        &self.health
+    }
+
+    fn take_shut_off(&self) -> Option<Duration> {
+        self.tripped.swap(false, Ordering::SeqCst).then_some(self.trip_for)
+    }
+
+    fn shut_off_elsewhere(&self) {
+        if self.health.mark_failing() {
+            info!("{} processor failed a payment on another replica, marking it as failing", self.name);
+        }
+    }
+}
+
+/// Picks one of the processors from a health view every clone shares.
+/// Health checks refresh the view, and a failed payment marks its processor as failing
+/// right away instead of waiting for the next check.
+#[derive(Clone, Debug)]
+pub struct ProcessorRouter {
+    // Sorted by priority
+    upstreams: Arc<Vec<Arc<Upstream>>>,
+}
+
+impl ProcessorRouter {
+    pub fn new(processors: Vec<ProcessorConfig>, health_check_interval: Duration) -> Self {
+        Self {
+            upstreams: Arc::new(processors.into_iter().map(|config| Arc::new(Upstream::new(config, health_check_interval))).collect()),
+        }
+    }
+
+    /// The first processor by priority that is not failing, or the very first when all are.
+    pub fn pick(&self) -> &Arc<Upstream> {
+        self.upstreams.iter()
+            .find(|upstream| !upstream.is_failing())
+            .unwrap_or(&self.upstreams[0])
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    /// Name and failing flag of every processor, for the metrics.
+    pub fn health(&self) -> Vec<(String, bool)> {
+        self.upstreams.iter()
+            .map(|upstream| (upstream.name.clone(), upstream.is_failing()))
+            .collect()
+    }
+
+    pub fn mark_failing(&self, upstream: &Upstream) {
+        if upstream.health.mark_failing() {
+            warn!("{} processor failed a payment, marking it as failing", upstream.name);
+            upstream.tripped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Starts refreshing the health view every interval, see `HealthMonitor::start`.
+    /// A processor another replica marked as failing after a failed payment is marked here
+    /// too, until the next health check.
+    pub fn start_health_checks(&self, client: Client, monitor: HealthMonitor) {
+        monitor.start(client, self.upstreams.clone());
+    }
+}
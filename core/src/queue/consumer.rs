@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use chrono::Utc;
+use tokio::sync::Semaphore;
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+use tracing::{info, error, warn};
+use crate::config::Settings;
+use crate::shutdown::ShutdownSignal;
+use crate::errors::CoreError;
+use crate::metrics::Metrics;
+use crate::queue::{Delivery, QueueBackend, QueueConsumerHandler, QueueKind, MessageWrapper};
+use crate::queue::retry::RetryPolicy;
+
+const MAX_RETRIES: u8 = 3;
+// How long a single consume call may wait for the next message on the main queue
+const CONSUME_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub struct Producer {
+    backend: Arc<dyn QueueBackend>,
+}
+
+impl Producer {
+    pub async fn new(backend: Arc<dyn QueueBackend>) -> Self {
+        Self {
+            backend,
+        }
+    }
+
+    pub async fn publish(&self, message: String) -> Result<(), CoreError> {
+        info!("Publishing message to queue");
+
+        // Wrap the message with retry information
+        match self.backend.publish(QueueKind::Main, MessageWrapper::new(message)).await {
+            Ok(_) => {
+                info!("Message published successfully");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to publish message: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Deliveries handed to a worker and not settled yet, keyed by delivery id, so that
+/// shutdown can abort the worker and requeue the message
+type InFlight = Arc<Mutex<HashMap<String, (Delivery, Option<AbortHandle>)>>>;
+
+/// Pulls from the main queue and runs up to `consumer_concurrency` handlers at once.
+///
+/// Messages are still dequeued in publish order, but with more than one worker they can
+/// complete out of order; only a concurrency of 1 keeps strict FIFO processing. Retries
+/// are re-published with a delay either way, so they never keep their original position.
+pub struct Consumer {
+    backend: Arc<dyn QueueBackend>,
+    retry_policy: RetryPolicy,
+    concurrency: usize,
+    metrics: Metrics,
+}
+
+/// Drains the DLQ every few seconds with up to `dlq_consumer_concurrency` handlers at once,
+/// stopping the cycle at the first failure.
+pub struct DLQConsumer {
+    backend: Arc<dyn QueueBackend>,
+    concurrency: usize,
+    metrics: Metrics,
+}
+
+/// Hands the delivery to a worker task and records it as in flight until the worker settles it.
+/// Workers are joined through `workers`, so shutdown can wait for every one of them.
+fn spawn_worker<F>(workers: &mut JoinSet<()>, in_flight: &InFlight, delivery: Delivery, work: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let id = delivery.id.clone();
+    in_flight.lock().unwrap().insert(id.clone(), (delivery, None));
+
+    // Reap the workers that are done, so the set only holds running ones
+    while workers.try_join_next().is_some() {}
+    let handle = workers.spawn(work);
+
+    // The worker may already be done and gone from the map, which is fine
+    if let Some(entry) = in_flight.lock().unwrap().get_mut(&id) {
+        entry.1 = Some(handle);
+    }
+}
+
+/// Called by a worker once its handler returns. `None` means shutdown already took the
+/// delivery back to requeue it, so the worker must not settle it as well.
+fn claim(in_flight: &InFlight, id: &str) -> Option<Delivery> {
+    in_flight.lock().unwrap().remove(id).map(|(delivery, _)| delivery)
+}
+
+/// Waits up to the shutdown deadline for every worker to finish, then aborts whatever is
+/// still running and returns those messages to their queue untouched. No worker is left
+/// running on return, so the connections they use can be closed right after.
+async fn drain(backend: &Arc<dyn QueueBackend>, mut workers: JoinSet<()>, in_flight: &InFlight, deadline: Duration) {
+    let all_settled = async {
+        while workers.join_next().await.is_some() {}
+    };
+
+    if tokio::time::timeout(deadline, all_settled).await.is_ok() {
+        info!("All in-flight messages settled");
+        return;
+    }
+
+    let unfinished: Vec<(Delivery, Option<AbortHandle>)> = in_flight.lock().unwrap()
+        .drain()
+        .map(|(_, entry)| entry)
+        .collect();
+    warn!("Shutdown deadline reached, requeueing {} unfinished messages", unfinished.len());
+
+    for (mut delivery, handle) in unfinished {
+        if let Some(handle) = handle {
+            handle.abort();
+        }
+
+        // Not a failed attempt, so it keeps its retry count and is due right away
+        delivery.wrapper.next_attempt_at = None;
+        if let Err(e) = backend.nack(delivery).await {
+            error!("Failed to requeue unfinished message: {}", e);
+        }
+    }
+
+    // Aborted workers stop at their next await point
+    workers.abort_all();
+    while workers.join_next().await.is_some() {}
+}
+
+async fn quarantine(backend: &Arc<dyn QueueBackend>, mut delivery: Delivery, error: &CoreError) -> Result<(), CoreError> {
+    delivery.wrapper.last_error = Some(error.to_string());
+    backend.publish(QueueKind::Quarantine, delivery.wrapper.clone()).await?;
+    backend.ack(&delivery).await
+}
+
+impl DLQConsumer {
+    pub async fn new(backend: Arc<dyn QueueBackend>, settings: Settings, metrics: Metrics) -> Self {
+        Self {
+            backend,
+            concurrency: settings.dlq_consumer_concurrency.max(1),
+            metrics,
+        }
+    }
+
+    pub async fn start_consuming(&self, handler: impl QueueConsumerHandler, mut shutdown: ShutdownSignal) -> JoinHandle<()> {
+        info!("Starting to consume messages from DLQ with {} workers", self.concurrency);
+
+        let backend = self.backend.clone();
+        let handler = Arc::new(handler);
+        let metrics = self.metrics.clone();
+        let concurrency = self.concurrency;
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let in_flight: InFlight = Arc::new(Mutex::new(HashMap::new()));
+        let mut workers = JoinSet::new();
+
+        tokio::spawn(async move {
+            loop {
+                // Wait for 3 seconds before attempting to consume from DLQ
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(3)) => {},
+                    _ = shutdown.triggered() => break,
+                }
+
+                match backend.len(QueueKind::DeadLetter).await {
+                    Ok(0) => continue,
+                    Ok(depth) => info!("Checking DLQ for messages ({} queued)", depth),
+                    Err(e) => error!("Failed to read DLQ depth: {}", e),
+                }
+
+                let failed = Arc::new(AtomicBool::new(false));
+
+                // Stop pulling more messages until next cycle once one has failed
+                while !failed.load(Ordering::SeqCst) && !shutdown.is_triggered() {
+                    let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+                    // A failure pushes its message straight back, so check again now that
+                    // the worker which failed may have let go of its permit
+                    if failed.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let delivery = match backend.consume(QueueKind::DeadLetter, Duration::ZERO).await {
+                        Ok(Some(delivery)) => delivery,
+                        Ok(None) => {
+                            // No more messages in the DLQ
+                            info!("No more messages in DLQ");
+                            break;
+                        },
+                        Err(e) => {
+                            error!("Error receiving message from DLQ: {}", e);
+                            break;
+                        }
+                    };
+
+                    let backend = backend.clone();
+                    let handler = handler.clone();
+                    let failed = failed.clone();
+                    let metrics = metrics.clone();
+                    let worker_in_flight = in_flight.clone();
+                    let id = delivery.id.clone();
+                    let message = delivery.wrapper.message.clone();
+                    let retry_count = delivery.wrapper.retry_count;
+
+                    spawn_worker(&mut workers, &in_flight, delivery, async move {
+                        let _in_flight = metrics.track_in_flight(QueueKind::DeadLetter);
+                        info!("Processing message from DLQ (retry count: {})", retry_count);
+
+                        // Process the original message
+                        let result = handler.consume(message).await;
+
+                        let Some(mut delivery) = claim(&worker_in_flight, &id) else {
+                            return;
+                        };
+
+                        match result {
+                            Ok(_) => {
+                                info!("DLQ message processed successfully");
+                                if let Err(e) = backend.ack(&delivery).await {
+                                    error!("Failed to acknowledge DLQ message {}: {}", delivery.id, e);
+                                }
+                            },
+                            Err(e) if e.is_retryable() => {
+                                error!("Error processing message from DLQ: {}", e);
+                                failed.store(true, Ordering::SeqCst);
+
+                                // Push the message back to the DLQ, ready for the next cycle.
+                                // The retry time left over from the main queue would hold it
+                                // out of the DLQ where it can be neither listed nor replayed
+                                delivery.wrapper.next_attempt_at = None;
+                                let message = delivery.wrapper.message.clone();
+                                match backend.nack(delivery).await {
+                                    Ok(_) => handler.dead_lettered(&message, retry_count, &e).await,
+                                    Err(push_err) => error!("Failed to push message back to DLQ: {}", push_err),
+                                }
+                            },
+                            Err(e) => {
+                                // Retrying it every cycle would never help, so park it for an operator
+                                error!("DLQ message cannot succeed, moving to quarantine: {}", e);
+                                let message = delivery.wrapper.message.clone();
+                                match quarantine(&backend, delivery, &e).await {
+                                    Ok(_) => handler.quarantined(&message, retry_count, &e).await,
+                                    Err(push_err) => error!("Failed to quarantine DLQ message: {}", push_err),
+                                }
+                            }
+                        }
+
+                        drop(permit);
+                    });
+                }
+
+                if shutdown.is_triggered() {
+                    break;
+                }
+
+                // Let the in-flight messages of this cycle settle before sleeping again
+                drop(semaphore.acquire_many(concurrency as u32).await.unwrap());
+            }
+
+            info!("Stopped pulling from DLQ");
+            drain(&backend, workers, &in_flight, shutdown.deadline()).await;
+        })
+    }
+}
+
+impl Consumer {
+    pub async fn new(backend: Arc<dyn QueueBackend>, settings: Settings, metrics: Metrics) -> Self {
+        Self {
+            backend,
+            retry_policy: RetryPolicy::new(&settings),
+            concurrency: settings.consumer_concurrency.max(1),
+            metrics,
+        }
+    }
+
+    pub async fn start_consuming(&self, handler: impl QueueConsumerHandler, mut shutdown: ShutdownSignal) -> JoinHandle<()> {
+        info!("Starting to consume messages from queue with {} workers", self.concurrency);
+
+        let backend = self.backend.clone();
+        let handler = Arc::new(handler);
+        let retry_policy = self.retry_policy.clone();
+        let metrics = self.metrics.clone();
+        let concurrency = self.concurrency;
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let in_flight: InFlight = Arc::new(Mutex::new(HashMap::new()));
+        let mut workers = JoinSet::new();
+
+        tokio::spawn(async move {
+            while !shutdown.is_triggered() {
+                // Only pull a message once a worker is free to take it
+                let permit = tokio::select! {
+                    permit = semaphore.clone().acquire_owned() => permit.unwrap(),
+                    _ = shutdown.triggered() => break,
+                };
+
+                // Not raced against shutdown: a read cut short could strand the message it fetched
+                let delivery = match backend.consume(QueueKind::Main, CONSUME_WAIT).await {
+                    Ok(Some(delivery)) => delivery,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Error receiving message from queue: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let backend = backend.clone();
+                let handler = handler.clone();
+                let retry_policy = retry_policy.clone();
+                let metrics = metrics.clone();
+                let worker_in_flight = in_flight.clone();
+                let id = delivery.id.clone();
+                let message = delivery.wrapper.message.clone();
+
+                info!("Received message {} (retry count: {})", id, delivery.wrapper.retry_count);
+
+                spawn_worker(&mut workers, &in_flight, delivery, async move {
+                    let _in_flight = metrics.track_in_flight(QueueKind::Main);
+                    handle_delivery(backend, handler.as_ref(), &retry_policy, &metrics, &id, message, &worker_in_flight).await;
+                    drop(permit);
+                });
+            }
+
+            info!("Stopped pulling from queue");
+            drain(&backend, workers, &in_flight, shutdown.deadline()).await;
+        })
+    }
+}
+
+async fn handle_delivery(
+    backend: Arc<dyn QueueBackend>,
+    handler: &impl QueueConsumerHandler,
+    retry_policy: &RetryPolicy,
+    metrics: &Metrics,
+    id: &str,
+    message: String,
+    in_flight: &InFlight,
+) {
+    // Process the original message
+    let result = handler.consume(message).await;
+
+    let Some(mut delivery) = claim(in_flight, id) else {
+        return;
+    };
+
+    match result {
+        Ok(_) => {
+            info!("Message processed successfully");
+            if let Err(e) = backend.ack(&delivery).await {
+                error!("Failed to acknowledge message {}: {}", delivery.id, e);
+            }
+        },
+        Err(e) => {
+            // Increment retry count
+            let new_retry_count = delivery.wrapper.retry_count + 1;
+            error!("Error processing message: {}, retry count: {}", e, new_retry_count);
+
+            let now = Utc::now().timestamp_millis();
+            let retry_delay = retry_policy.delay(new_retry_count);
+
+            delivery.wrapper.retry_count = new_retry_count;
+            delivery.wrapper.next_attempt_at = Some(now + retry_delay.as_millis() as i64);
+            delivery.wrapper.first_failed_at = delivery.wrapper.first_failed_at.or(Some(now));
+            delivery.wrapper.last_error = Some(e.to_string());
+            let message = delivery.wrapper.message.clone();
+
+            // A message that can never succeed skips the DLQ, whose consumer would only call
+            // the processor again before parking it anyway. Others are handed back until due
+            // while below the retry limit, and pushed to the DLQ after that
+            if !e.is_retryable() {
+                info!("Message cannot succeed on retry, moving to quarantine");
+                match quarantine(&backend, delivery, &e).await {
+                    Ok(_) => handler.quarantined(&message, new_retry_count, &e).await,
+                    Err(push_err) => error!("Failed to quarantine message: {}", push_err),
+                }
+            } else if new_retry_count < MAX_RETRIES {
+                info!("Scheduling retry {} in {:?}", new_retry_count, retry_delay);
+                match backend.nack(delivery).await {
+                    Ok(_) => {
+                        metrics.inc_retries();
+                        handler.retrying(&message, new_retry_count, &e).await
+                    },
+                    Err(push_err) => error!("Failed to requeue message: {}", push_err),
+                }
+            } else {
+                info!("Message retry limit reached, moving to DLQ");
+                match backend.dead_letter(delivery).await {
+                    Ok(_) => {
+                        metrics.inc_dead_lettered();
+                        handler.dead_lettered(&message, new_retry_count, &e).await
+                    },
+                    Err(push_err) => error!("Failed to requeue message: {}", push_err),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use super::*;
+    use crate::queue::memory::MemoryQueue;
+    use crate::shutdown::ShutdownCoordinator;
+
+    fn settings() -> Settings {
+        Settings::for_tests(json!({
+            "consumer_concurrency": 2,
+            "retry_base_delay_ms": 10,
+            "retry_jitter_ms": 0,
+        }))
+    }
+
+    /// Fails the first `failures` calls with `error`, then succeeds.
+    struct FlakyHandler {
+        calls: Arc<AtomicUsize>,
+        failures: usize,
+        error: CoreError,
+    }
+
+    #[async_trait]
+    impl QueueConsumerHandler for FlakyHandler {
+        async fn consume(&self, _message: String) -> Result<(), CoreError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(self.error.clone());
+            }
+            Ok(())
+        }
+    }
+
+    /// Runs the consumer over the memory backend until `done` holds or a second has passed.
+    async fn run_until(backend: Arc<dyn QueueBackend>, handler: FlakyHandler, done: impl AsyncFn() -> bool) {
+        let consumer = Consumer::new(backend, settings(), Metrics::new()).await;
+        let mut shutdown = ShutdownCoordinator::new(Duration::from_millis(100));
+        shutdown.track("consumer", consumer.start_consuming(handler, shutdown.signal()).await);
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        while !done().await && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        shutdown.drain().await;
+    }
+
+    #[tokio::test]
+    async fn retries_a_transient_failure_until_it_succeeds() {
+        let backend: Arc<dyn QueueBackend> = Arc::new(MemoryQueue::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        Producer::new(backend.clone()).await.publish("payment".to_string()).await.unwrap();
+
+        let handler = FlakyHandler {
+            calls: calls.clone(),
+            failures: 1,
+            error: CoreError::ProcessorTransient("busy".to_string()),
+        };
+        run_until(backend.clone(), handler, async || calls.load(Ordering::SeqCst) == 2).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(backend.len(QueueKind::Main).await.unwrap(), 0);
+        assert_eq!(backend.len(QueueKind::DeadLetter).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_once_retries_run_out() {
+        let backend: Arc<dyn QueueBackend> = Arc::new(MemoryQueue::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        Producer::new(backend.clone()).await.publish("payment".to_string()).await.unwrap();
+
+        let handler = FlakyHandler {
+            calls: calls.clone(),
+            failures: usize::MAX,
+            error: CoreError::ProcessorTransient("busy".to_string()),
+        };
+        let dlq = backend.clone();
+        run_until(backend.clone(), handler, async || dlq.len(QueueKind::DeadLetter).await.unwrap() == 1).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), MAX_RETRIES as usize);
+        assert_eq!(backend.len(QueueKind::Main).await.unwrap(), 0);
+
+        let dead_letters = backend.list(QueueKind::DeadLetter, None, 10).await.unwrap();
+        assert_eq!(dead_letters[0].wrapper.retry_count, MAX_RETRIES);
+        assert!(dead_letters[0].wrapper.last_error.as_deref().unwrap().contains("busy"));
+    }
+
+    #[tokio::test]
+    async fn quarantines_a_message_that_cannot_succeed_without_retrying() {
+        let backend: Arc<dyn QueueBackend> = Arc::new(MemoryQueue::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        Producer::new(backend.clone()).await.publish("payment".to_string()).await.unwrap();
+
+        let handler = FlakyHandler {
+            calls: calls.clone(),
+            failures: usize::MAX,
+            error: CoreError::Serialization("unreadable".to_string()),
+        };
+        let quarantine = backend.clone();
+        run_until(backend.clone(), handler, async || quarantine.len(QueueKind::Quarantine).await.unwrap() == 1).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.len(QueueKind::Main).await.unwrap(), 0);
+        assert_eq!(backend.len(QueueKind::DeadLetter).await.unwrap(), 0);
+    }
+    #[tokio::test]
+    async fn dlq_failures_go_back_listable_whatever_their_old_retry_time() {
+        let backend: Arc<dyn QueueBackend> = Arc::new(MemoryQueue::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut wrapper = MessageWrapper::new("payment".to_string());
+        wrapper.next_attempt_at = Some(Utc::now().timestamp_millis() + 3_600_000);
+        backend.publish(QueueKind::DeadLetter, wrapper).await.unwrap();
+
+        let handler = FlakyHandler {
+            calls: calls.clone(),
+            failures: usize::MAX,
+            error: CoreError::ProcessorTransient("busy".to_string()),
+        };
+        let consumer = DLQConsumer::new(backend.clone(), settings(), Metrics::new()).await;
+        let mut shutdown = ShutdownCoordinator::new(Duration::from_millis(100));
+        shutdown.track("dlq consumer", consumer.start_consuming(handler, shutdown.signal()).await);
+
+        // The first cycle starts three seconds in
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while calls.load(Ordering::SeqCst) == 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.drain().await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let dead_letters = backend.list(QueueKind::DeadLetter, None, 10).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].wrapper.next_attempt_at, None);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::Utc;
+use tokio::sync::Notify;
+use crate::errors::CoreError;
+use crate::queue::{QueueBackend, QueueKind, MessageWrapper, Delivery, PrepareMove};
+
+#[derive(Debug, Default)]
+struct Lane {
+    ready: Mutex<VecDeque<(u64, MessageWrapper)>>,
+    notify: Notify,
+    // Ids of the deliveries consumers hold, until they are settled
+    held: Mutex<HashSet<u64>>,
+    // Ids the delayed retries are pushed back with once due; both count towards `len`
+    delayed: Mutex<HashSet<u64>>,
+}
+
+impl Lane {
+    fn push(&self, id: u64, wrapper: MessageWrapper) {
+        self.ready.lock().unwrap().push_back((id, wrapper));
+        self.notify.notify_one();
+    }
+
+    fn pop(&self) -> Option<(u64, MessageWrapper)> {
+        self.ready.lock().unwrap().pop_front()
+    }
+
+    /// Stops tracking a delivery being settled, failing when a purge already dropped it.
+    fn release(&self, delivery: &Delivery) -> Result<(), CoreError> {
+        let released = delivery.id.parse::<u64>()
+            .is_ok_and(|id| self.held.lock().unwrap().remove(&id));
+
+        if released {
+            Ok(())
+        } else {
+            Err(CoreError::Queue(format!("Message {} was purged before it was settled", delivery.id)))
+        }
+    }
+}
+
+/// In-process backend for tests and single-node runs. Nothing survives a restart, and
+/// a message handed out by `consume` is only tracked until it is settled.
+#[derive(Debug)]
+pub struct MemoryQueue {
+    lanes: HashMap<QueueKind, Arc<Lane>>,
+    sequence: AtomicU64,
+}
+
+impl MemoryQueue {
+    pub fn new() -> Self {
+        let lanes = [QueueKind::Main, QueueKind::DeadLetter, QueueKind::Quarantine]
+            .into_iter()
+            .map(|kind| (kind, Arc::new(Lane::default())))
+            .collect();
+
+        Self {
+            lanes,
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    fn lane(&self, queue: QueueKind) -> &Arc<Lane> {
+        &self.lanes[&queue]
+    }
+
+    fn next_id(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl QueueBackend for MemoryQueue {
+    async fn publish(&self, queue: QueueKind, wrapper: MessageWrapper) -> Result<(), CoreError> {
+        self.lane(queue).push(self.next_id(), wrapper);
+        Ok(())
+    }
+
+    async fn consume(&self, queue: QueueKind, wait: Duration) -> Result<Option<Delivery>, CoreError> {
+        let lane = self.lane(queue);
+        let deadline = tokio::time::Instant::now() + wait;
+
+        loop {
+            // Register interest before checking, so a push in between is not missed
+            let notified = lane.notify.notified();
+
+            if let Some((id, wrapper)) = lane.pop() {
+                lane.held.lock().unwrap().insert(id);
+                return Ok(Some(Delivery {
+                    id: id.to_string(),
+                    queue,
+                    wrapper,
+                    lease: None,
+                }));
+            }
+
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), CoreError> {
+        self.lane(delivery.queue).release(delivery)
+    }
+
+    async fn nack(&self, delivery: Delivery) -> Result<(), CoreError> {
+        let lane = self.lane(delivery.queue).clone();
+        lane.release(&delivery)?;
+        let id = self.next_id();
+        let delay = delivery.wrapper.next_attempt_at
+            .map(|at| (at - Utc::now().timestamp_millis()).max(0) as u64)
+            .unwrap_or(0);
+
+        // Stays delayed until it lands back on the ready queue, unless a purge drops it first
+        lane.delayed.lock().unwrap().insert(id);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            if lane.delayed.lock().unwrap().remove(&id) {
+                lane.push(id, delivery.wrapper);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn dead_letter(&self, delivery: Delivery) -> Result<(), CoreError> {
+        self.lane(delivery.queue).release(&delivery)?;
+        self.lane(QueueKind::DeadLetter).push(self.next_id(), delivery.wrapper);
+        Ok(())
+    }
+
+    async fn list(&self, queue: QueueKind, after: Option<String>, limit: usize) -> Result<Vec<Delivery>, CoreError> {
+        let after = match after {
+            Some(id) => Some(id.parse::<u64>().map_err(|e| CoreError::Validation(format!("Invalid message id {}: {}", id, e)))?),
+            None => None,
+        };
+
+        // Delayed retries land back out of order, so sort by id to keep paging stable
+        let mut ready: Vec<(u64, MessageWrapper)> = self.lane(queue).ready.lock().unwrap()
+            .iter()
+            .filter(|(id, _)| after.is_none_or(|after| *id > after))
+            .cloned()
+            .collect();
+        ready.sort_by_key(|(id, _)| *id);
+
+        Ok(ready
+            .into_iter()
+            .take(limit)
+            .map(|(id, wrapper)| Delivery { id: id.to_string(), queue, wrapper, lease: None })
+            .collect())
+    }
+
+    async fn get(&self, queue: QueueKind, id: &str) -> Result<Option<Delivery>, CoreError> {
+        let id = id.parse::<u64>().map_err(|e| CoreError::Validation(format!("Invalid message id {}: {}", id, e)))?;
+
+        // Handed out messages are not on the ready queue, so only idle ones are found
+        let found = self.lane(queue).ready.lock().unwrap()
+            .iter()
+            .find(|(entry_id, _)| *entry_id == id)
+            .map(|(_, wrapper)| wrapper.clone());
+
+        Ok(found.map(|wrapper| Delivery { id: id.to_string(), queue, wrapper, lease: None }))
+    }
+
+    async fn move_to(
+        &self,
+        from: QueueKind,
+        to: QueueKind,
+        id: &str,
+        prepare: &PrepareMove,
+    ) -> Result<bool, CoreError> {
+        let id = id.parse::<u64>().map_err(|e| CoreError::Validation(format!("Invalid message id {}: {}", id, e)))?;
+
+        // Handed out messages are not on the ready queue, so only idle ones can be moved
+        let taken = {
+            let mut ready = self.lane(from).ready.lock().unwrap();
+            ready.iter()
+                .position(|(entry_id, _)| *entry_id == id)
+                .and_then(|index| ready.remove(index))
+        };
+
+        let Some((_, mut wrapper)) = taken else {
+            return Ok(false);
+        };
+
+        prepare(&mut wrapper);
+        self.lane(to).push(self.next_id(), wrapper);
+        Ok(true)
+    }
+
+    async fn purge(&self, queue: QueueKind) -> Result<usize, CoreError> {
+        let lane = self.lane(queue);
+        let mut purged = 0;
+
+        for ids in [&lane.held, &lane.delayed] {
+            let mut ids = ids.lock().unwrap();
+            purged += ids.len();
+            ids.clear();
+        }
+
+        let mut ready = lane.ready.lock().unwrap();
+        purged += ready.len();
+        ready.clear();
+        Ok(purged)
+    }
+
+    async fn len(&self, queue: QueueKind) -> Result<usize, CoreError> {
+        let lane = self.lane(queue);
+        let ready = lane.ready.lock().unwrap().len();
+        let held = lane.held.lock().unwrap().len();
+        let delayed = lane.delayed.lock().unwrap().len();
+        Ok(ready + held + delayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapper(message: &str) -> MessageWrapper {
+        MessageWrapper::new(message.to_string())
+    }
+
+    #[tokio::test]
+    async fn consumes_in_publish_order_and_forgets_acked_messages() {
+        let queue = MemoryQueue::new();
+        queue.publish(QueueKind::Main, wrapper("first")).await.unwrap();
+        queue.publish(QueueKind::Main, wrapper("second")).await.unwrap();
+
+        let first = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(first.wrapper.message, "first");
+        // Still counted until it is settled
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 2);
+
+        queue.ack(&first).await.unwrap();
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 1);
+
+        let second = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(second.wrapper.message, "second");
+        queue.ack(&second).await.unwrap();
+
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 0);
+        assert!(queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn consume_waits_for_a_publish() {
+        let queue = Arc::new(MemoryQueue::new());
+
+        let publisher = queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            publisher.publish(QueueKind::Main, wrapper("late")).await.unwrap();
+        });
+
+        let delivery = queue.consume(QueueKind::Main, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(delivery.unwrap().wrapper.message, "late");
+    }
+
+    #[tokio::test]
+    async fn nack_redelivers_once_due() {
+        let queue = MemoryQueue::new();
+        queue.publish(QueueKind::Main, wrapper("payment")).await.unwrap();
+
+        let mut delivery = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        delivery.wrapper.retry_count = 1;
+        delivery.wrapper.next_attempt_at = Some(Utc::now().timestamp_millis() + 100);
+        queue.nack(delivery).await.unwrap();
+
+        // Delayed, but still held by the queue
+        assert!(queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().is_none());
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 1);
+
+        let retried = queue.consume(QueueKind::Main, Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(retried.wrapper.message, "payment");
+        assert_eq!(retried.wrapper.retry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letter_moves_the_message_to_the_dlq() {
+        let queue = MemoryQueue::new();
+        queue.publish(QueueKind::Main, wrapper("payment")).await.unwrap();
+
+        let delivery = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        queue.dead_letter(delivery).await.unwrap();
+
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 0);
+        assert_eq!(queue.len(QueueKind::DeadLetter).await.unwrap(), 1);
+
+        let listed = queue.list(QueueKind::DeadLetter, None, 10).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].wrapper.message, "payment");
+
+        let found = queue.get(QueueKind::DeadLetter, &listed[0].id).await.unwrap().unwrap();
+        assert_eq!(found.wrapper.message, "payment");
+        assert!(queue.get(QueueKind::Main, &listed[0].id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn move_to_skips_messages_a_consumer_holds() {
+        let queue = MemoryQueue::new();
+        queue.publish(QueueKind::DeadLetter, wrapper("held")).await.unwrap();
+        queue.publish(QueueKind::DeadLetter, wrapper("idle")).await.unwrap();
+
+        let held = queue.consume(QueueKind::DeadLetter, Duration::ZERO).await.unwrap().unwrap();
+        let idle = queue.list(QueueKind::DeadLetter, None, 10).await.unwrap().remove(0);
+        let reset = |wrapper: &mut MessageWrapper| wrapper.retry_count = 0;
+
+        assert!(!queue.move_to(QueueKind::DeadLetter, QueueKind::Main, &held.id, &reset).await.unwrap());
+        assert!(queue.move_to(QueueKind::DeadLetter, QueueKind::Main, &idle.id, &reset).await.unwrap());
+        // Already gone
+        assert!(!queue.move_to(QueueKind::DeadLetter, QueueKind::Main, &idle.id, &reset).await.unwrap());
+
+        let moved = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        assert_eq!(moved.wrapper.message, "idle");
+    }
+
+    #[tokio::test]
+    async fn purge_drops_held_and_delayed_messages() {
+        let queue = MemoryQueue::new();
+        for message in ["held", "delayed", "ready"] {
+            queue.publish(QueueKind::Main, wrapper(message)).await.unwrap();
+        }
+
+        let held = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        let mut delayed = queue.consume(QueueKind::Main, Duration::ZERO).await.unwrap().unwrap();
+        delayed.wrapper.next_attempt_at = Some(Utc::now().timestamp_millis() + 50);
+        queue.nack(delayed).await.unwrap();
+
+        assert_eq!(queue.purge(QueueKind::Main).await.unwrap(), 3);
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 0);
+
+        // The holder can no longer settle it, and the delayed retry never comes back
+        assert!(queue.ack(&held).await.is_err());
+        assert!(queue.consume(QueueKind::Main, Duration::from_millis(200)).await.unwrap().is_none());
+        assert_eq!(queue.len(QueueKind::Main).await.unwrap(), 0);
+    }
+}
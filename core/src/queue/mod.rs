@@ -1,10 +1,146 @@
+use std::sync::Arc;
+use std::time::Duration;
 use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::config::{Settings, QueueBackendKind};
+use crate::errors::CoreError;
 
 #[async_trait]
 pub trait QueueConsumerHandler: Send + Sync + 'static {
-    async fn consume(&self, message: String) -> Result<(), String>;
+    async fn consume(&self, message: String) -> Result<(), CoreError>;
+
+    /// Called after a failed message was handed back to its queue for another attempt.
+    async fn retrying(&self, _message: &str, _retry_count: u8, _error: &CoreError) {}
+
+    /// Called after a failed message was moved to the DLQ, or put back on it.
+    async fn dead_lettered(&self, _message: &str, _retry_count: u8, _error: &CoreError) {}
+
+    /// Called after a message that can never succeed was moved to the quarantine.
+    async fn quarantined(&self, _message: &str, _retry_count: u8, _error: &CoreError) {}
+}
+
+/// The logical queues every backend keeps for the payment topic.
+/// Nothing consumes the quarantine; operators park messages there for good.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueueKind {
+    Main,
+    DeadLetter,
+    Quarantine,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageWrapper {
+    pub message: String,
+    pub retry_count: u8,
+    // Unix epoch milliseconds; both unset until the message fails for the first time
+    #[serde(default)]
+    pub next_attempt_at: Option<i64>,
+    #[serde(default)]
+    pub first_failed_at: Option<i64>,
+    // Error returned by the most recent failed attempt
+    #[serde(default)]
+    pub last_error: Option<String>,
 }
 
+impl MessageWrapper {
+    pub fn new(message: String) -> Self {
+        Self {
+            message,
+            retry_count: 0,
+            next_attempt_at: None,
+            first_failed_at: None,
+            last_error: None,
+        }
+    }
+}
+
+/// Updates a message while `QueueBackend::move_to` carries it over to another queue.
+pub type PrepareMove = dyn Fn(&mut MessageWrapper) + Send + Sync;
+
+/// What a consumer's hold on a message is checked against when it settles the message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lease {
+    // Postgres: a token written to the row on every consume
+    Token(Uuid),
+    // Redis: the delivery count of the pending entry, which a reclaim by any consumer bumps
+    Deliveries(u64),
+}
+
+/// A message handed out by `QueueBackend::consume`. It stays owned by the consumer
+/// until it is passed back through `ack`, `nack` or `dead_letter`.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    // Backend specific handle: stream entry id, row id or in-process sequence
+    pub id: String,
+    pub queue: QueueKind,
+    pub wrapper: MessageWrapper,
+    // Set by backends whose hold on a consumed message can run out while the handler is
+    // still working; settling checks it so a message claimed again is left to its new owner
+    pub lease: Option<Lease>,
+}
+
+#[async_trait]
+pub trait QueueBackend: std::fmt::Debug + Send + Sync + 'static {
+    async fn publish(&self, queue: QueueKind, wrapper: MessageWrapper) -> Result<(), CoreError>;
+
+    /// Waits up to `wait` for the next message; a zero `wait` only checks what is ready.
+    async fn consume(&self, queue: QueueKind, wait: Duration) -> Result<Option<Delivery>, CoreError>;
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), CoreError>;
+
+    /// Gives the message back to its queue with the wrapper the caller updated.
+    /// It is not redelivered before `wrapper.next_attempt_at`.
+    async fn nack(&self, delivery: Delivery) -> Result<(), CoreError>;
+
+    async fn dead_letter(&self, delivery: Delivery) -> Result<(), CoreError>;
+
+    /// Messages held by the queue, including delayed retries and unacknowledged deliveries.
+    async fn len(&self, queue: QueueKind) -> Result<usize, CoreError>;
+
+    /// Up to `limit` ready messages in delivery order, starting after the `after` id.
+    /// Listing does not take the messages out of the queue.
+    async fn list(&self, queue: QueueKind, after: Option<String>, limit: usize) -> Result<Vec<Delivery>, CoreError>;
+
+    /// One message by id, left in the queue like `list` leaves it.
+    async fn get(&self, queue: QueueKind, id: &str) -> Result<Option<Delivery>, CoreError>;
+
+    /// Moves a single message by id from one queue to another in one step, letting `prepare`
+    /// update it on the way. Returns `false` when the message is no longer in `from`, or when
+    /// a consumer holds it right now; it is left alone then, since the consumer settles it.
+    async fn move_to(
+        &self,
+        from: QueueKind,
+        to: QueueKind,
+        id: &str,
+        prepare: &PrepareMove,
+    ) -> Result<bool, CoreError>;
+
+    /// Drops every message in the queue, including those a consumer holds and delayed
+    /// retries, and returns how many were removed. Settling a held one afterwards fails.
+    async fn purge(&self, queue: QueueKind) -> Result<usize, CoreError>;
+
+    /// Releases the backend's own connections at shutdown.
+    async fn close(&self) {}
+}
+
+mod consumer;
+mod memory;
+mod postgres;
 mod redis;
+mod retry;
+
+pub use consumer::{Producer, Consumer, DLQConsumer};
 
-pub use redis::{Producer, Consumer, DLQConsumer};
+pub async fn connect(settings: &Settings, db_pool: Pool) -> Arc<dyn QueueBackend> {
+    match settings.queue_backend {
+        QueueBackendKind::Redis => {
+            let queue = Arc::new(redis::RedisQueue::new(settings).await);
+            queue.start_promoter();
+            queue
+        },
+        QueueBackendKind::Memory => Arc::new(memory::MemoryQueue::new()),
+        QueueBackendKind::Postgres => Arc::new(postgres::PostgresQueue::new(db_pool, settings)),
+    }
+}
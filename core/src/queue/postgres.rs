@@ -0,0 +1,311 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use tracing::{info, error};
+use uuid::Uuid;
+use crate::config::Settings;
+use crate::errors::CoreError;
+use crate::queue::{QueueBackend, QueueKind, MessageWrapper, Delivery, Lease, PrepareMove};
+
+// Leases a ready row to this consumer; rows locked by another transaction are skipped
+// instead of waited on, and a lease that expires makes the row visible again. The token
+// changes on every lease, so only the latest holder can settle the row
+const CONSUME_QUERY: &str = "
+    UPDATE queue_messages SET locked_until = now() + make_interval(secs => $3), lease_token = $4
+    WHERE id = (
+        SELECT id FROM queue_messages
+        WHERE topic = $1 AND queue = $2 AND available_at <= now()
+          AND (locked_until IS NULL OR locked_until < now())
+        ORDER BY id
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING id, payload";
+
+/// Postgres backend built on `SELECT ... FOR UPDATE SKIP LOCKED`, sharing the payments pool.
+#[derive(Debug)]
+pub struct PostgresQueue {
+    db_pool: Pool,
+    topic: String,
+    lease_secs: f64,
+    poll_interval: Duration,
+}
+
+fn queue_name(queue: QueueKind) -> &'static str {
+    match queue {
+        QueueKind::Main => "main",
+        QueueKind::DeadLetter => "dead_letter",
+        QueueKind::Quarantine => "quarantine",
+    }
+}
+
+fn serialize(wrapper: &MessageWrapper) -> Result<String, CoreError> {
+    serde_json::to_string(wrapper).map_err(|e| CoreError::Serialization(format!("Failed to serialize message: {}", e)))
+}
+
+impl PostgresQueue {
+    pub fn new(db_pool: Pool, settings: &Settings) -> Self {
+        info!("Using Postgres queue for topic {}", settings.payment_topic);
+
+        Self {
+            db_pool,
+            topic: settings.payment_topic.clone(),
+            lease_secs: settings.claim_min_idle_ms as f64 / 1000.0,
+            poll_interval: Duration::from_millis(settings.retry_poll_interval_ms),
+        }
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Object, CoreError> {
+        self.db_pool.get().await.map_err(|e| {
+            error!("Failed to get Postgres connection: {}", e);
+            CoreError::Queue(format!("Failed to get Postgres connection: {}", e))
+        })
+    }
+
+    async fn try_consume(&self, queue: QueueKind) -> Result<Option<Delivery>, CoreError> {
+        let client = self.client().await?;
+        let lease = Uuid::new_v4();
+
+        let row = client
+            .query_opt(CONSUME_QUERY, &[&self.topic, &queue_name(queue), &self.lease_secs, &lease])
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to consume from Postgres queue: {}", e)))?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let id: i64 = row.get(0);
+        let payload: String = row.get(1);
+
+        match to_delivery(queue, id, &payload) {
+            Ok(delivery) => Ok(Some(Delivery { lease: Some(Lease::Token(lease)), ..delivery })),
+            Err(e) => {
+                // If we can't deserialize, we can't retry properly, so just drop it
+                error!("Dropping unreadable queue row {}: {}", id, e);
+                client
+                    .execute("DELETE FROM queue_messages WHERE id = $1", &[&id])
+                    .await
+                    .map_err(|e| CoreError::Queue(format!("Failed to drop queue row {}: {}", id, e)))?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn settle(&self, delivery: &Delivery, target: QueueKind) -> Result<(), CoreError> {
+        let id = parse_id(&delivery.id)?;
+        let payload = serialize(&delivery.wrapper)?;
+        // Epoch seconds; NULL makes the row available right away
+        let available_at = delivery.wrapper.next_attempt_at
+            .filter(|_| target == delivery.queue)
+            .map(|at| at as f64 / 1000.0);
+
+        let updated = self.client().await?
+            .execute(
+                "UPDATE queue_messages
+                 SET queue = $2, payload = $3, available_at = COALESCE(to_timestamp($4), now()),
+                     locked_until = NULL, lease_token = NULL
+                 WHERE id = $1 AND lease_token = $5",
+                &[&id, &queue_name(target), &payload, &available_at, &lease_token(delivery)],
+            )
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to requeue row {}: {}", id, e)))?;
+
+        expect_owned(id, updated)
+    }
+}
+
+/// A settle that matched no row lost its lease: the row went to another consumer, which
+/// now decides what happens to it.
+fn expect_owned(id: i64, updated: u64) -> Result<(), CoreError> {
+    if updated == 0 {
+        return Err(CoreError::Queue(format!("Lease on queue row {} was lost before it was settled", id)));
+    }
+
+    Ok(())
+}
+
+fn lease_token(delivery: &Delivery) -> Option<Uuid> {
+    match delivery.lease {
+        Some(Lease::Token(token)) => Some(token),
+        _ => None,
+    }
+}
+
+fn parse_id(id: &str) -> Result<i64, CoreError> {
+    id.parse::<i64>().map_err(|e| CoreError::Validation(format!("Invalid queue row id {}: {}", id, e)))
+}
+
+fn to_delivery(queue: QueueKind, id: i64, payload: &str) -> Result<Delivery, CoreError> {
+    serde_json::from_str::<MessageWrapper>(payload)
+        .map(|wrapper| Delivery { id: id.to_string(), queue, wrapper, lease: None })
+        .map_err(|e| CoreError::Serialization(format!("failed to deserialize queue row {}: {}", id, e)))
+}
+
+#[async_trait]
+impl QueueBackend for PostgresQueue {
+    async fn publish(&self, queue: QueueKind, wrapper: MessageWrapper) -> Result<(), CoreError> {
+        let payload = serialize(&wrapper)?;
+
+        self.client().await?
+            .execute(
+                "INSERT INTO queue_messages (topic, queue, payload) VALUES ($1, $2, $3)",
+                &[&self.topic, &queue_name(queue), &payload],
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                error!("Failed to publish message to Postgres: {}", e);
+                CoreError::Queue(format!("Failed to publish message to Postgres: {}", e))
+            })
+    }
+
+    async fn consume(&self, queue: QueueKind, wait: Duration) -> Result<Option<Delivery>, CoreError> {
+        let deadline = tokio::time::Instant::now() + wait;
+
+        // There is no blocking read, so poll until something is ready or the wait is over
+        loop {
+            if let Some(delivery) = self.try_consume(queue).await? {
+                return Ok(Some(delivery));
+            }
+
+            if tokio::time::Instant::now() + self.poll_interval > deadline {
+                return Ok(None);
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), CoreError> {
+        let id = parse_id(&delivery.id)?;
+
+        let deleted = self.client().await?
+            .execute("DELETE FROM queue_messages WHERE id = $1 AND lease_token = $2", &[&id, &lease_token(delivery)])
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to acknowledge row {}: {}", id, e)))?;
+
+        expect_owned(id, deleted)
+    }
+
+    async fn nack(&self, delivery: Delivery) -> Result<(), CoreError> {
+        self.settle(&delivery, delivery.queue).await
+    }
+
+    async fn dead_letter(&self, delivery: Delivery) -> Result<(), CoreError> {
+        self.settle(&delivery, QueueKind::DeadLetter).await
+    }
+
+    async fn list(&self, queue: QueueKind, after: Option<String>, limit: usize) -> Result<Vec<Delivery>, CoreError> {
+        let after = match after {
+            Some(id) => parse_id(&id)?,
+            None => 0,
+        };
+
+        let rows = self.client().await?
+            .query(
+                "SELECT id, payload FROM queue_messages
+                 WHERE topic = $1 AND queue = $2 AND id > $3
+                 ORDER BY id
+                 LIMIT $4",
+                &[&self.topic, &queue_name(queue), &after, &(limit as i64)],
+            )
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to list queue rows: {}", e)))?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| match to_delivery(queue, row.get(0), row.get(1)) {
+                Ok(delivery) => Some(delivery),
+                Err(e) => {
+                    error!("Skipping unreadable queue row: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn get(&self, queue: QueueKind, id: &str) -> Result<Option<Delivery>, CoreError> {
+        let id = parse_id(id)?;
+
+        let row = self.client().await?
+            .query_opt(
+                "SELECT id, payload FROM queue_messages
+                 WHERE topic = $1 AND queue = $2 AND id = $3",
+                &[&self.topic, &queue_name(queue), &id],
+            )
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to read queue row {}: {}", id, e)))?;
+
+        row.map(|row| to_delivery(queue, row.get(0), row.get(1))).transpose()
+    }
+
+    async fn move_to(
+        &self,
+        from: QueueKind,
+        to: QueueKind,
+        id: &str,
+        prepare: &PrepareMove,
+    ) -> Result<bool, CoreError> {
+        let id = parse_id(id)?;
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await
+            .map_err(|e| CoreError::Queue(format!("Failed to move queue row {}: {}", id, e)))?;
+
+        // A row that was ever leased and not settled since still belongs to its consumer,
+        // even once the lease ran out, so only rows without a token can be moved
+        let row = transaction
+            .query_opt(
+                "SELECT payload FROM queue_messages
+                 WHERE id = $1 AND topic = $2 AND queue = $3 AND lease_token IS NULL
+                 FOR UPDATE SKIP LOCKED",
+                &[&id, &self.topic, &queue_name(from)],
+            )
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to read queue row {}: {}", id, e)))?;
+
+        let Some(row) = row else {
+            return Ok(false);
+        };
+
+        let mut wrapper = to_delivery(from, id, row.get(0))?.wrapper;
+        prepare(&mut wrapper);
+        let payload = serialize(&wrapper)?;
+
+        transaction
+            .execute(
+                "UPDATE queue_messages SET queue = $2, payload = $3, available_at = now() WHERE id = $1",
+                &[&id, &queue_name(to), &payload],
+            )
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to move queue row {}: {}", id, e)))?;
+
+        transaction.commit().await
+            .map(|_| true)
+            .map_err(|e| CoreError::Queue(format!("Failed to move queue row {}: {}", id, e)))
+    }
+
+    async fn purge(&self, queue: QueueKind) -> Result<usize, CoreError> {
+        self.client().await?
+            .execute(
+                "DELETE FROM queue_messages WHERE topic = $1 AND queue = $2",
+                &[&self.topic, &queue_name(queue)],
+            )
+            .await
+            .map(|purged| purged as usize)
+            .map_err(|e| CoreError::Queue(format!("Failed to purge queue: {}", e)))
+    }
+
+    async fn len(&self, queue: QueueKind) -> Result<usize, CoreError> {
+        let row = self.client().await?
+            .query_one(
+                "SELECT COUNT(1) FROM queue_messages WHERE topic = $1 AND queue = $2",
+                &[&self.topic, &queue_name(queue)],
+            )
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to count queue rows: {}", e)))?;
+
+        let count: i64 = row.get(0);
+        Ok(count as usize)
+    }
+}
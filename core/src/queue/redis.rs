@@ -1,33 +1,151 @@
-use redis::{Client, AsyncCommands};
+use redis::{Client, AsyncCommands, RedisResult, Script};
+use redis::aio::Connection;
+use redis::streams::{StreamClaimReply, StreamId, StreamRangeReply, StreamReadOptions, StreamReadReply};
 use tracing::{info, error, warn};
-use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
 use crate::config::Settings;
-use crate::queue::{QueueConsumerHandler};
-use std::time::Duration;
-use std::num::NonZeroUsize;
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct MessageWrapper {
-    message: String,
-    retry_count: u8,
-}
-
-#[derive(Clone, Debug)]
-pub struct Producer {
+use crate::errors::CoreError;
+use crate::queue::{QueueBackend, QueueKind, MessageWrapper, Delivery, Lease, PrepareMove};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+
+// Field under which the serialized MessageWrapper is stored on each stream entry
+const PAYLOAD_FIELD: &str = "payload";
+// Maximum number of due retries moved back onto a stream per promoter tick
+const PROMOTE_BATCH_SIZE: usize = 100;
+
+// Settles the pending entry ARGV[2] of the group ARGV[1] on the stream KEYS[1], then puts
+// the payload ARGV[4], if any, on the stream KEYS[2], or in the retry set KEYS[3] scored by
+// ARGV[5] when that is set. The entry must still be pending on the consumer ARGV[6] with
+// the delivery count ARGV[7]; returns 0 without touching it when it is not, i.e. when it
+// was reclaimed by a consumer that now settles it, or purged
+const SETTLE_SCRIPT: &str = r#"
+local pending = redis.call('XPENDING', KEYS[1], ARGV[1], ARGV[2], ARGV[2], 1)
+if #pending == 0 or pending[1][2] ~= ARGV[6] or pending[1][4] ~= tonumber(ARGV[7]) then
+    return 0
+end
+redis.call('XACK', KEYS[1], ARGV[1], ARGV[2])
+redis.call('XDEL', KEYS[1], ARGV[2])
+if ARGV[4] == '' then
+    return 1
+end
+if ARGV[5] == '' then
+    redis.call('XADD', KEYS[2], '*', ARGV[3], ARGV[4])
+else
+    redis.call('ZADD', KEYS[3], ARGV[5], ARGV[4])
+end
+return 1
+"#;
+
+// Moves one entry (ARGV[2]) from the stream KEYS[1] to KEYS[2] with the payload ARGV[4].
+// Returns 1 once moved, 0 when the entry is gone, and -1 when it is pending on a consumer
+// of the group ARGV[1], which settles it itself
+const MOVE_SCRIPT: &str = r#"
+if #redis.call('XPENDING', KEYS[1], ARGV[1], ARGV[2], ARGV[2], 1) > 0 then
+    return -1
+end
+if redis.call('XDEL', KEYS[1], ARGV[2]) == 0 then
+    return 0
+end
+redis.call('XADD', KEYS[2], '*', ARGV[3], ARGV[4])
+return 1
+"#;
+
+// Atomically moves every due member of the retry set (KEYS[1]) onto the stream (KEYS[2]),
+// so concurrent promoters on different replicas never move the same message twice
+const PROMOTE_SCRIPT: &str = r#"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, member in ipairs(due) do
+    redis.call('ZREM', KEYS[1], member)
+    redis.call('XADD', KEYS[2], '*', ARGV[3], member)
+end
+return #due
+"#;
+
+/// Redis Streams backend: every replica joins one consumer group per stream, entries are
+/// acknowledged and deleted once settled, and delayed retries wait in a sorted set scored
+/// by their next attempt time until the promoter moves them back onto the stream.
+pub struct RedisQueue {
     client: Client,
+    // Idle connections. Each in-flight worker checks out its own, since blocking reads
+    // cannot share a multiplexed connection; the pool grows to the peak concurrency
+    idle: Mutex<Vec<Connection>>,
     queue_name: String,
+    dlq_name: String,
+    quarantine_name: String,
+    group_name: String,
+    consumer_name: String,
+    claim_min_idle_ms: u64,
+    poll_interval: Duration,
+    groups_ready: AtomicBool,
+    last_claim: Mutex<HashMap<QueueKind, Instant>>,
+}
+
+impl std::fmt::Debug for RedisQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisQueue")
+            .field("queue_name", &self.queue_name)
+            .field("group_name", &self.group_name)
+            .field("consumer_name", &self.consumer_name)
+            .finish_non_exhaustive()
+    }
+}
+
+fn retry_set_name(stream: &str) -> String {
+    format!("{}_retry", stream)
+}
+
+/// Creates the consumer group (and the stream itself) if it does not exist yet.
+/// Starting from `0` makes the group pick up entries published before it was created.
+async fn ensure_group(conn: &mut Connection, stream: &str, group: &str) -> RedisResult<()> {
+    match conn.xgroup_create_mkstream::<_, _, _, ()>(stream, group, "0").await {
+        Ok(_) => {
+            info!("Created consumer group {} on stream {}", group, stream);
+            Ok(())
+        },
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+        Err(e) => Err(e),
+    }
 }
 
-impl Producer {
-    pub async fn new(settings: Settings) -> Self {
-        let redis_url = settings.redis_url;
+fn parse_entry(entry: &StreamId) -> Result<MessageWrapper, CoreError> {
+    let serialized = entry
+        .get::<String>(PAYLOAD_FIELD)
+        .ok_or_else(|| CoreError::Serialization(format!("stream entry {} has no {} field", entry.id, PAYLOAD_FIELD)))?;
+
+    serde_json::from_str::<MessageWrapper>(&serialized)
+        .map_err(|e| CoreError::Serialization(format!("failed to deserialize message wrapper: {}", e)))
+}
+
+fn serialize(wrapper: &MessageWrapper) -> Result<String, CoreError> {
+    serde_json::to_string(wrapper).map_err(|e| {
+        error!("Failed to serialize message: {}", e);
+        CoreError::Serialization(format!("Failed to serialize message: {}", e))
+    })
+}
+
+impl RedisQueue {
+    pub async fn new(settings: &Settings) -> Self {
+        let redis_url = settings.redis_url.clone();
 
         match Client::open(redis_url.clone()) {
             Ok(client) => {
                 info!("Connected to Redis at {}", redis_url);
                 Self {
                     client,
-                    queue_name: settings.payment_topic,
+                    idle: Mutex::new(Vec::new()),
+                    queue_name: settings.payment_topic.clone(),
+                    dlq_name: format!("{}_dlq", settings.payment_topic),
+                    quarantine_name: format!("{}_quarantine", settings.payment_topic),
+                    group_name: settings.consumer_group.clone(),
+                    consumer_name: settings.consumer_name.clone(),
+                    claim_min_idle_ms: settings.claim_min_idle_ms,
+                    poll_interval: Duration::from_millis(settings.retry_poll_interval_ms),
+                    groups_ready: AtomicBool::new(false),
+                    last_claim: Mutex::new(HashMap::new()),
                 }
             },
             Err(e) => {
@@ -37,251 +155,438 @@ impl Producer {
         }
     }
 
-    pub async fn publish(&self, message: String) -> Result<(), String> {
-        info!("Publishing message to queue: {}", self.queue_name);
+    fn stream_name(&self, queue: QueueKind) -> &str {
+        match queue {
+            QueueKind::Main => &self.queue_name,
+            QueueKind::DeadLetter => &self.dlq_name,
+            QueueKind::Quarantine => &self.quarantine_name,
+        }
+    }
 
-        let mut conn = match self.client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(e) => {
+    async fn checkout(&self) -> Result<Connection, CoreError> {
+        let idle = self.idle.lock().unwrap().pop();
+
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => self.client.get_async_connection().await.map_err(|e| {
                 error!("Failed to get Redis connection: {}", e);
-                return Err(format!("Failed to get Redis connection: {}", e));
-            }
+                CoreError::Queue(format!("Failed to get Redis connection: {}", e))
+            })?,
         };
 
-        // Wrap the message with retry information
-        let wrapped_message = MessageWrapper {
-            message,
-            retry_count: 0,
+        if !self.groups_ready.load(Ordering::SeqCst) {
+            for stream in [&self.queue_name, &self.dlq_name, &self.quarantine_name] {
+                ensure_group(&mut conn, stream, &self.group_name).await.map_err(|e| {
+                    CoreError::Queue(format!("Failed to create consumer group on stream {}: {}", stream, e))
+                })?;
+            }
+            self.groups_ready.store(true, Ordering::SeqCst);
+        }
+
+        Ok(conn)
+    }
+
+    // Only connections whose last command succeeded are reused
+    fn checkin(&self, conn: Connection) {
+        self.idle.lock().unwrap().push(conn);
+    }
+
+    fn claim_due(&self, queue: QueueKind) -> bool {
+        let claim_interval = Duration::from_millis(self.claim_min_idle_ms / 2);
+        let last_claim = self.last_claim.lock().unwrap();
+        last_claim.get(&queue).is_none_or(|at| at.elapsed() >= claim_interval)
+    }
+
+    /// Takes over one entry that has been pending on some other consumer for longer than
+    /// `claim_min_idle_ms`, i.e. a message a crashed or stalled replica read but never
+    /// acknowledged. Returns it with the delivery count the claim left it at.
+    async fn reclaim(&self, conn: &mut Connection, stream: &str) -> RedisResult<Option<(StreamId, u64)>> {
+        let (_, claimed, _): (String, StreamClaimReply, redis::Value) = redis::cmd("XAUTOCLAIM")
+            .arg(stream)
+            .arg(&self.group_name)
+            .arg(&self.consumer_name)
+            .arg(self.claim_min_idle_ms)
+            .arg("0-0")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(conn)
+            .await?;
+
+        let Some(entry) = claimed.ids.into_iter().next() else {
+            return Ok(None);
         };
 
-        // Serialize the wrapped message
-        let serialized = match serde_json::to_string(&wrapped_message) {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Failed to serialize message: {}", e);
-                return Err(format!("Failed to serialize message: {}", e));
+        // [[id, consumer, idle, deliveries]]
+        let pending: Vec<(String, String, u64, u64)> = redis::cmd("XPENDING")
+            .arg(stream)
+            .arg(&self.group_name)
+            .arg(&entry.id)
+            .arg(&entry.id)
+            .arg(1)
+            .query_async(conn)
+            .await?;
+
+        Ok(pending.into_iter().next().map(|(_, _, _, deliveries)| (entry, deliveries)))
+    }
+
+    /// Reads the next entry delivered to this consumer, blocking up to `wait` when non-zero.
+    async fn read_group(&self, conn: &mut Connection, stream: &str, wait: Duration) -> RedisResult<Option<StreamId>> {
+        let mut options = StreamReadOptions::default()
+            .group(&self.group_name, &self.consumer_name)
+            .count(1);
+
+        if !wait.is_zero() {
+            options = options.block(wait.as_millis() as usize);
+        }
+
+        let reply: Option<StreamReadReply> = conn.xread_options(&[stream], &[">"], &options).await?;
+
+        Ok(reply.and_then(|r| r.keys.into_iter().flat_map(|k| k.ids).next()))
+    }
+
+    /// The next entry for this consumer and its delivery count.
+    async fn next_entry(&self, conn: &mut Connection, queue: QueueKind, wait: Duration) -> RedisResult<Option<(StreamId, u64)>> {
+        let stream = self.stream_name(queue);
+
+        // Entries left pending by a dead replica come first, then new ones
+        if self.claim_due(queue) {
+            if let Some((entry, deliveries)) = self.reclaim(conn, stream).await? {
+                warn!("Reclaimed stuck message {} from stream {}", entry.id, stream);
+                return Ok(Some((entry, deliveries)));
             }
+            self.last_claim.lock().unwrap().insert(queue, Instant::now());
+        }
+
+        // A new entry is on its first delivery
+        Ok(self.read_group(conn, stream, wait).await?.map(|entry| (entry, 1)))
+    }
+
+    /// Settles the delivery, putting `payload` on `target` unless it is an acknowledgement.
+    /// Refused once the entry is no longer this delivery's: another consumer reclaimed it
+    /// and decides what happens to it now, or it was purged.
+    async fn settle(&self, delivery: &Delivery, target: QueueKind, payload: Option<String>, due_at: Option<i64>) -> Result<(), CoreError> {
+        let stream = self.stream_name(delivery.queue);
+        let target_stream = self.stream_name(target);
+        let deliveries = match delivery.lease {
+            Some(Lease::Deliveries(deliveries)) => deliveries,
+            _ => 0,
         };
 
-        // Use RPUSH to add the message to the end of the list
-        match conn.rpush::<_, _, ()>(&self.queue_name, serialized).await {
+        let mut conn = self.checkout().await?;
+        let result: RedisResult<i64> = Script::new(SETTLE_SCRIPT)
+            .key(stream)
+            .key(target_stream)
+            .key(retry_set_name(target_stream))
+            .arg(&self.group_name)
+            .arg(&delivery.id)
+            .arg(PAYLOAD_FIELD)
+            .arg(payload.unwrap_or_default())
+            .arg(due_at.map(|at| at.to_string()).unwrap_or_default())
+            .arg(&self.consumer_name)
+            .arg(deliveries)
+            .invoke_async(&mut conn)
+            .await;
+
+        match result {
+            Ok(0) => {
+                self.checkin(conn);
+                Err(CoreError::Queue(format!("Entry {} on stream {} was reclaimed or purged before it was settled", delivery.id, stream)))
+            },
             Ok(_) => {
-                info!("Message published to Redis successfully");
+                self.checkin(conn);
                 Ok(())
             },
             Err(e) => {
-                error!("Failed to publish message to Redis: {}", e);
-                Err(format!("Failed to publish message to Redis: {}", e))
+                error!("Failed to settle entry {} on stream {}: {}", delivery.id, stream, e);
+                Err(CoreError::Queue(format!("Failed to settle entry {} on stream {}: {}", delivery.id, stream, e)))
             }
         }
     }
-}
 
-pub struct Consumer {
-    client: Client,
-    queue_name: String,
-    dlq_name: String,
-}
+    /// Appends the delivery to `target` (or parks it in the target's retry set until `due_at`)
+    /// and settles the delivered entry in one script, so the message is neither lost nor
+    /// duplicated between the two.
+    async fn move_entry(&self, delivery: &Delivery, target: QueueKind, due_at: Option<i64>) -> Result<(), CoreError> {
+        let payload = serialize(&delivery.wrapper)?;
 
-pub struct DLQConsumer {
-    client: Client,
-    dlq_name: String,
-}
-
-impl DLQConsumer {
-    pub async fn new(settings: Settings) -> Self {
-        let redis_url = settings.redis_url.clone();
-        let topic = settings.payment_topic.clone();
-        let dlq_name = format!("{}_dlq", topic);
-
-        match Client::open(redis_url.clone()) {
-            Ok(client) => {
-                info!("Connected to Redis at {} for DLQ consumer", redis_url);
-                Self {
-                    client,
-                    dlq_name,
-                }
-            },
-            Err(e) => {
-                error!("Failed to connect to Redis at {}: {}", redis_url, e);
-                panic!("Failed to connect to Redis for DLQ consumer");
-            }
-        }
+        self.settle(delivery, target, Some(payload), due_at).await
     }
 
-    pub async fn start_consuming(&self, handler: impl QueueConsumerHandler) {
-        info!("Starting to consume messages from DLQ: {}", self.dlq_name);
+    /// Spawns the task that moves due retries back onto their streams.
+    pub fn start_promoter(self: &Arc<Self>) {
+        info!("Starting to promote due retries every {:?}", self.poll_interval);
 
-        let client = self.client.clone();
-        let dlq_name = self.dlq_name.clone();
+        let queue = self.clone();
 
         tokio::spawn(async move {
-            let mut conn = client.get_async_connection().await.unwrap();
+            let script = Script::new(PROMOTE_SCRIPT);
 
             loop {
-                // Wait for 3 seconds before attempting to consume from DLQ
-                tokio::time::sleep(Duration::from_secs(3)).await;
-                info!("Checking DLQ for messages: {}", dlq_name);
+                tokio::time::sleep(queue.poll_interval).await;
+
+                let mut conn = match queue.checkout().await {
+                    Ok(conn) => conn,
+                    Err(_) => continue,
+                };
 
-                loop {
-                    // Use LPOP (non-blocking) to get the first message from the DLQ
-                    let result: redis::RedisResult<Option<String>> = conn.lpop(&dlq_name, Some(NonZeroUsize::new(1).unwrap())).await;
+                let mut healthy = true;
+                for stream in [&queue.queue_name, &queue.dlq_name] {
+                    let retry_set = retry_set_name(stream);
+                    let result: RedisResult<usize> = script
+                        .key(&retry_set)
+                        .key(stream)
+                        .arg(Utc::now().timestamp_millis())
+                        .arg(PROMOTE_BATCH_SIZE)
+                        .arg(PAYLOAD_FIELD)
+                        .invoke_async(&mut conn)
+                        .await;
 
                     match result {
-                        Ok(Some(serialized_wrapper)) => {
-                            // Deserialize the wrapped message
-                            let wrapper = match serde_json::from_str::<MessageWrapper>(&serialized_wrapper) {
-                                Ok(w) => w,
-                                Err(e) => {
-                                    error!("Failed to deserialize message wrapper from DLQ: {}", e);
-                                    break;
-                                }
-                            };
-
-                            info!("Processing message from DLQ (retry count: {})", wrapper.retry_count);
-
-                            // Process the original message
-                            match handler.consume(wrapper.message.clone()).await {
-                                Ok(_) => {
-                                    info!("DLQ message processed successfully, continuing to next message");
-                                    // Continue processing more messages
-                                },
-                                Err(e) => {
-                                    error!("Error processing message from DLQ: {}", e);
-
-                                    // Push the message back to the DLQ
-                                    if let Err(push_err) = conn.rpush::<_, _, ()>(&dlq_name, serialized_wrapper).await {
-                                        error!("Failed to push message back to DLQ {}: {}", dlq_name, push_err);
-                                    }
-
-                                    // Stop processing more messages until next cycle
-                                    break;
-                                }
-                            }
-                        },
-                        Ok(None) => {
-                            // No more messages in the DLQ
-                            info!("No more messages in DLQ");
-                            break;
-                        },
+                        Ok(0) => {},
+                        Ok(promoted) => info!("Promoted {} due retries back to stream {}", promoted, stream),
                         Err(e) => {
-                            error!("Error receiving message from DLQ: {}", e);
+                            error!("Failed to promote due retries from {}: {}", retry_set, e);
+                            healthy = false;
                             break;
                         }
                     }
                 }
+
+                if healthy {
+                    queue.checkin(conn);
+                }
             }
         });
     }
 }
 
-impl Consumer {
-    pub async fn new(settings: Settings) -> Self {
-        let redis_url = settings.redis_url.clone();
-        let topic = settings.payment_topic.clone();
+#[async_trait]
+impl QueueBackend for RedisQueue {
+    async fn publish(&self, queue: QueueKind, wrapper: MessageWrapper) -> Result<(), CoreError> {
+        let stream = self.stream_name(queue);
+        info!("Publishing message to stream: {}", stream);
 
-        match Client::open(redis_url.clone()) {
-            Ok(client) => {
-                info!("Connected to Redis at {}", redis_url);
-                Self {
-                    client,
-                    queue_name: topic.to_string(),
-                    dlq_name: format!("{}_dlq", topic),
-                }
+        let serialized = serialize(&wrapper)?;
+        let mut conn = self.checkout().await?;
+
+        // Use XADD to append the message to the stream
+        match conn.xadd::<_, _, _, _, String>(stream, "*", &[(PAYLOAD_FIELD, serialized)]).await {
+            Ok(_) => {
+                self.checkin(conn);
+                Ok(())
             },
             Err(e) => {
-                error!("Failed to connect to Redis at {}: {}", redis_url, e);
-                panic!("Failed to connect to Redis");
+                error!("Failed to publish message to Redis: {}", e);
+                Err(CoreError::Queue(format!("Failed to publish message to Redis: {}", e)))
             }
         }
     }
 
-    pub async fn start_consuming(&self, handler: impl QueueConsumerHandler) {
-        info!("Starting to consume messages from queue: {}", self.queue_name);
-        info!("DLQ configured as: {}", self.dlq_name);
+    async fn consume(&self, queue: QueueKind, wait: Duration) -> Result<Option<Delivery>, CoreError> {
+        let mut conn = self.checkout().await?;
+
+        loop {
+            let (entry, deliveries) = match self.next_entry(&mut conn, queue, wait).await {
+                Ok(Some(next)) => next,
+                Ok(None) => {
+                    self.checkin(conn);
+                    return Ok(None);
+                },
+                Err(e) => {
+                    error!("Error receiving message from Redis: {}", e);
+                    if e.code() == Some("NOGROUP") {
+                        self.groups_ready.store(false, Ordering::SeqCst);
+                    }
+                    return Err(CoreError::Queue(format!("Error receiving message from Redis: {}", e)));
+                }
+            };
+
+            match parse_entry(&entry) {
+                Ok(wrapper) => {
+                    self.checkin(conn);
+                    return Ok(Some(Delivery {
+                        id: entry.id,
+                        queue,
+                        wrapper,
+                        lease: Some(Lease::Deliveries(deliveries)),
+                    }));
+                },
+                Err(e) => {
+                    // If we can't deserialize, we can't retry properly, so just drop it
+                    error!("Dropping unreadable entry {}: {}", entry.id, e);
+                    let stream = self.stream_name(queue);
+                    if let Err(ack_err) = redis::pipe()
+                        .atomic()
+                        .xack(stream, &self.group_name, &[&entry.id]).ignore()
+                        .xdel(stream, &[&entry.id]).ignore()
+                        .query_async::<_, ()>(&mut conn)
+                        .await {
+                        error!("Failed to acknowledge entry {}: {}", entry.id, ack_err);
+                    }
+                }
+            }
+        }
+    }
 
-        let client = self.client.clone();
-        let queue_name = self.queue_name.clone();
-        let dlq_name = self.dlq_name.clone();
+    async fn ack(&self, delivery: &Delivery) -> Result<(), CoreError> {
+        // Acknowledge and delete so the stream does not grow unbounded
+        self.settle(delivery, delivery.queue, None, None).await
+    }
 
-        tokio::spawn(async move {
-            loop {
-                let mut conn = match client.get_async_connection().await {
-                    Ok(conn) => conn,
-                    Err(e) => {
-                        error!("Failed to get Redis connection: {}", e);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        continue;
-                    }
-                };
+    async fn nack(&self, delivery: Delivery) -> Result<(), CoreError> {
+        let due_at = delivery.wrapper.next_attempt_at
+            .filter(|at| *at > Utc::now().timestamp_millis());
 
-                // Use BLPOP to get the first message from the list
-                let result: redis::RedisResult<Option<(String, String)>> = conn.blpop(&queue_name, 0f64).await;
-
-                match result {
-                    Ok(Some((_, serialized_wrapper))) => {
-                        // Deserialize the wrapped message
-                        let wrapper = match serde_json::from_str::<MessageWrapper>(&serialized_wrapper) {
-                            Ok(w) => w,
-                            Err(e) => {
-                                error!("Failed to deserialize message wrapper: {}", e);
-                                // If we can't deserialize, we can't retry properly, so just continue
-                                continue;
-                            }
-                        };
-
-                        info!("Received message from Redis (retry count: {})", wrapper.retry_count);
-
-                        // Process the original message
-                        match handler.consume(wrapper.message.clone()).await {
-                            Ok(_) => {
-                                info!("Message processed successfully");
-                            },
-                            Err(e) => {
-                                // Increment retry count
-                                let new_retry_count = wrapper.retry_count + 1;
-                                error!("Error processing message: {}, retry count: {}", e, new_retry_count);
-
-                                // Create a new wrapper with incremented retry count
-                                let new_wrapper = MessageWrapper {
-                                    message: wrapper.message,
-                                    retry_count: new_retry_count,
-                                };
-
-                                // Serialize the new wrapper
-                                let serialized = match serde_json::to_string(&new_wrapper) {
-                                    Ok(s) => s,
-                                    Err(ser_err) => {
-                                        error!("Failed to serialize message wrapper: {}", ser_err);
-                                        continue;
-                                    }
-                                };
-
-                                // If retry count is less than 3, push back to the original queue
-                                // Otherwise, push to the DLQ
-                                let target_queue = if new_retry_count < 3 {
-                                    &queue_name
-                                } else {
-                                    info!("Message retry limit reached, moving to DLQ: {}", dlq_name);
-                                    &dlq_name
-                                };
-
-                                // Push the message to the appropriate queue
-                                if let Err(push_err) = conn.rpush::<_, _, ()>(target_queue, serialized).await {
-                                    error!("Failed to push message to queue {}: {}", target_queue, push_err);
-                                }
-                            }
-                        }
-                    },
-                    Ok(None) => {
-                        warn!("BLPOP didn't return any messages")
-                    },
-                    Err(e) => {
-                        error!("Error receiving message from Redis: {}", e);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                    }
+        self.move_entry(&delivery, delivery.queue, due_at).await
+    }
+
+    async fn dead_letter(&self, delivery: Delivery) -> Result<(), CoreError> {
+        self.move_entry(&delivery, QueueKind::DeadLetter, None).await
+    }
+
+    async fn purge(&self, queue: QueueKind) -> Result<usize, CoreError> {
+        let stream = self.stream_name(queue);
+        let mut conn = self.checkout().await?;
+
+        // Trim rather than delete the stream, and recreate the group so the entries consumers
+        // hold are dropped from its pending list too; their settles then move nothing back
+        match redis::pipe()
+            .atomic()
+            .cmd("XTRIM").arg(stream).arg("MAXLEN").arg(0)
+            .zcard(retry_set_name(stream))
+            .del(retry_set_name(stream)).ignore()
+            .cmd("XGROUP").arg("DESTROY").arg(stream).arg(&self.group_name).ignore()
+            .cmd("XGROUP").arg("CREATE").arg(stream).arg(&self.group_name).arg("0").ignore()
+            .query_async::<_, (usize, usize)>(&mut conn)
+            .await {
+            Ok((trimmed, delayed)) => {
+                self.checkin(conn);
+                info!("Purged {} messages from stream {}", trimmed + delayed, stream);
+                Ok(trimmed + delayed)
+            },
+            Err(e) => {
+                error!("Failed to purge stream {}: {}", stream, e);
+                Err(CoreError::Queue(format!("Failed to purge stream {}: {}", stream, e)))
+            }
+        }
+    }
+
+    async fn list(&self, queue: QueueKind, after: Option<String>, limit: usize) -> Result<Vec<Delivery>, CoreError> {
+        let stream = self.stream_name(queue);
+        // A leading `(` makes the range start exclusive
+        let start = after.map(|id| format!("({}", id)).unwrap_or_else(|| "-".to_string());
+        let mut conn = self.checkout().await?;
+
+        let reply: StreamRangeReply = match conn.xrange_count(stream, start, "+", limit).await {
+            Ok(reply) => {
+                self.checkin(conn);
+                reply
+            },
+            Err(e) => {
+                error!("Failed to list stream {}: {}", stream, e);
+                return Err(CoreError::Queue(format!("Failed to list stream {}: {}", stream, e)));
+            }
+        };
+
+        Ok(reply.ids
+            .into_iter()
+            .filter_map(|entry| match parse_entry(&entry) {
+                Ok(wrapper) => Some(Delivery { id: entry.id, queue, wrapper, lease: None }),
+                Err(e) => {
+                    warn!("Skipping unreadable entry {}: {}", entry.id, e);
+                    None
                 }
+            })
+            .collect())
+    }
+
+    async fn get(&self, queue: QueueKind, id: &str) -> Result<Option<Delivery>, CoreError> {
+        let stream = self.stream_name(queue);
+        let mut conn = self.checkout().await?;
+
+        let reply: StreamRangeReply = match conn.xrange(stream, id, id).await {
+            Ok(reply) => {
+                self.checkin(conn);
+                reply
+            },
+            Err(e) => {
+                error!("Failed to read entry {} from stream {}: {}", id, stream, e);
+                return Err(CoreError::Queue(format!("Failed to read entry {} from stream {}: {}", id, stream, e)));
             }
-        });
+        };
+
+        reply.ids
+            .into_iter()
+            .next()
+            .map(|entry| Ok(Delivery { wrapper: parse_entry(&entry)?, id: entry.id, queue, lease: None }))
+            .transpose()
+    }
+
+    async fn move_to(
+        &self,
+        from: QueueKind,
+        to: QueueKind,
+        id: &str,
+        prepare: &PrepareMove,
+    ) -> Result<bool, CoreError> {
+        let stream = self.stream_name(from);
+        let target_stream = self.stream_name(to);
+        let mut conn = self.checkout().await?;
+
+        let reply: StreamRangeReply = conn.xrange(stream, id, id).await
+            .map_err(|e| CoreError::Queue(format!("Failed to read entry {} from stream {}: {}", id, stream, e)))?;
+
+        let Some(entry) = reply.ids.into_iter().next() else {
+            self.checkin(conn);
+            return Ok(false);
+        };
+
+        // Entries are never rewritten in place, so what was read is still what gets moved
+        let mut wrapper = parse_entry(&entry)?;
+        prepare(&mut wrapper);
+        let payload = serialize(&wrapper)?;
+
+        let moved: i64 = Script::new(MOVE_SCRIPT)
+            .key(stream)
+            .key(target_stream)
+            .arg(&self.group_name)
+            .arg(id)
+            .arg(PAYLOAD_FIELD)
+            .arg(payload)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| CoreError::Queue(format!("Failed to move entry {} to stream {}: {}", id, target_stream, e)))?;
+        self.checkin(conn);
+
+        if moved < 0 {
+            info!("Left entry {} on stream {}, a consumer is handling it", id, stream);
+        }
+
+        Ok(moved > 0)
+    }
+
+    async fn close(&self) {
+        let closed = std::mem::take(&mut *self.idle.lock().unwrap());
+        info!("Closing {} idle Redis connections", closed.len());
+    }
+
+    async fn len(&self, queue: QueueKind) -> Result<usize, CoreError> {
+        let stream = self.stream_name(queue);
+        let mut conn = self.checkout().await?;
+
+        match redis::pipe()
+            .xlen(stream)
+            .zcard(retry_set_name(stream))
+            .query_async::<_, (usize, usize)>(&mut conn)
+            .await {
+            Ok((ready, delayed)) => {
+                self.checkin(conn);
+                Ok(ready + delayed)
+            },
+            Err(e) => {
+                error!("Failed to read length of stream {}: {}", stream, e);
+                Err(CoreError::Queue(format!("Failed to read length of stream {}: {}", stream, e)))
+            }
+        }
     }
 }
@@ -0,0 +1,74 @@
+use std::time::Duration;
+use rand::Rng;
+use crate::config::Settings;
+
+/// Exponential backoff used to schedule the next attempt of a failed message:
+/// `min(base * multiplier^(attempt - 1) + random(0..=jitter), max)`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    base_delay_ms: u64,
+    multiplier: f64,
+    jitter_ms: u64,
+    max_delay_ms: u64,
+}
+
+impl RetryPolicy {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            base_delay_ms: settings.retry_base_delay_ms,
+            multiplier: settings.retry_multiplier,
+            jitter_ms: settings.retry_jitter_ms,
+            max_delay_ms: settings.retry_max_delay_ms,
+        }
+    }
+
+    /// Delay before the given attempt, where `attempt` is the retry count after the failure (1-based).
+    pub fn delay(&self, attempt: u8) -> Duration {
+        let exponent = i32::from(attempt.saturating_sub(1));
+        let backoff = self.base_delay_ms as f64 * self.multiplier.powi(exponent);
+
+        let jitter = if self.jitter_ms > 0 {
+            rand::thread_rng().gen_range(0..=self.jitter_ms)
+        } else {
+            0
+        };
+
+        // The cap comes last so jitter never pushes a retry past it
+        let delay = (backoff + jitter as f64).min(self.max_delay_ms as f64) as u64;
+
+        Duration::from_millis(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter_ms: u64) -> RetryPolicy {
+        RetryPolicy {
+            base_delay_ms: 500,
+            multiplier: 2.0,
+            jitter_ms,
+            max_delay_ms: 10_000,
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = policy(0);
+
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_millis(1_000));
+        assert_eq!(policy.delay(4), Duration::from_millis(4_000));
+    }
+
+    #[test]
+    fn jitter_never_exceeds_the_cap() {
+        let policy = policy(5_000);
+
+        for attempt in 1..=20 {
+            assert!(policy.delay(attempt) <= Duration::from_millis(10_000));
+        }
+        assert_eq!(policy.delay(20), Duration::from_millis(10_000));
+    }
+}
@@ -0,0 +1,135 @@
+use std::future::{ready, Ready};
+use actix_web::{delete, get, post, web, FromRequest, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header;
+use serde::Deserialize;
+use serde_json::json;
+use crate::usecases::UseCases;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// The token the DLQ routes take as `Authorization: Bearer <token>`. Haproxy forwards them
+/// like any other route, so without a token configured they refuse every request.
+#[derive(Clone, Debug)]
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        Self(token.filter(|token| !token.is_empty()))
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+/// Taking it as an argument makes a handler refuse requests without the admin token.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let expected = req.app_data::<web::Data<AdminToken>>().and_then(|token| token.0.clone());
+        let presented = req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        ready(match (expected, presented) {
+            (Some(expected), Some(presented)) if expected == presented => Ok(Admin),
+            _ => Err(ErrorUnauthorized("admin token required")),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeadLetterParams {
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/admin/dlq")]
+pub async fn list_dead_letters(_: Admin, usecases: web::Data<UseCases>, query: web::Query<DeadLetterParams>) -> impl Responder {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    match usecases.dead_letters.list(query.after.clone(), limit).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            tracing::error!("Failed to list DLQ: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[get("/admin/dlq/{id}")]
+pub async fn get_dead_letter(_: Admin, usecases: web::Data<UseCases>, path: web::Path<String>) -> impl Responder {
+    match usecases.dead_letters.get(path.into_inner()).await {
+        Ok(Some(entry)) => HttpResponse::Ok().json(entry),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Failed to read DLQ entry: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[post("/admin/dlq/replay")]
+pub async fn replay_dead_letters(_: Admin, usecases: web::Data<UseCases>) -> impl Responder {
+    match usecases.dead_letters.replay_all().await {
+        Ok(replayed) => HttpResponse::Ok().json(json!({ "replayed": replayed })),
+        Err(e) => {
+            tracing::error!("Failed to replay DLQ: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[post("/admin/dlq/{id}/replay")]
+pub async fn replay_dead_letter(_: Admin, usecases: web::Data<UseCases>, path: web::Path<String>) -> impl Responder {
+    match usecases.dead_letters.replay(path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(json!({ "replayed": 1 })),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Failed to replay DLQ entry: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[post("/admin/dlq/quarantine")]
+pub async fn quarantine_dead_letters(_: Admin, usecases: web::Data<UseCases>) -> impl Responder {
+    match usecases.dead_letters.quarantine_all().await {
+        Ok(quarantined) => HttpResponse::Ok().json(json!({ "quarantined": quarantined })),
+        Err(e) => {
+            tracing::error!("Failed to quarantine DLQ: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[post("/admin/dlq/{id}/quarantine")]
+pub async fn quarantine_dead_letter(_: Admin, usecases: web::Data<UseCases>, path: web::Path<String>) -> impl Responder {
+    match usecases.dead_letters.quarantine(path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json(json!({ "quarantined": 1 })),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Failed to quarantine DLQ entry: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[delete("/admin/dlq")]
+pub async fn purge_dead_letters(_: Admin, usecases: web::Data<UseCases>) -> impl Responder {
+    match usecases.dead_letters.purge().await {
+        Ok(purged) => HttpResponse::Ok().json(json!({ "purged": purged })),
+        Err(e) => {
+            tracing::error!("Failed to purge DLQ: {}", e);
+            e.error_response()
+        },
+    }
+}
@@ -0,0 +1,21 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::json;
+use crate::usecases::UseCases;
+
+// The process is up and serving requests; dependencies are left to readiness
+#[get("/health/live")]
+pub async fn live() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "up" }))
+}
+
+#[get("/health/ready")]
+pub async fn ready(usecases: web::Data<UseCases>) -> impl Responder {
+    let readiness = usecases.check_readiness.clone().execute().await;
+
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        tracing::warn!("Not ready: {:?}", readiness.checks);
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
@@ -0,0 +1,15 @@
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use crate::usecases::UseCases;
+
+#[get("/metrics")]
+pub async fn get_metrics(usecases: web::Data<UseCases>) -> impl Responder {
+    match usecases.get_metrics.clone().execute().await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to render metrics: {}", e);
+            e.error_response()
+        },
+    }
+}
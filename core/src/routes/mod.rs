@@ -1,3 +1,18 @@
 mod payment;
+mod dead_letters;
+mod metrics;
+mod health;
 
-pub use payment::{process_payment, get_summary};
\ No newline at end of file
+pub use payment::{process_payment, get_payment, get_summary, json_error};
+pub use metrics::{get_metrics};
+pub use health::{live, ready};
+pub use dead_letters::{
+    AdminToken,
+    list_dead_letters,
+    get_dead_letter,
+    replay_dead_letters,
+    replay_dead_letter,
+    quarantine_dead_letters,
+    quarantine_dead_letter,
+    purge_dead_letters,
+};
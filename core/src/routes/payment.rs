@@ -1,6 +1,10 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header;
 use serde::Deserialize;
-use crate::models::Payment;
+use serde_json::json;
+use crate::errors::{CoreError, FieldError};
+use crate::models::{Payment, PaymentOutcome, SummaryWindow};
 use crate::usecases::UseCases;
 
 #[derive(Deserialize)]
@@ -9,23 +13,76 @@ pub struct SummaryParams {
     to: Option<String>,
 }
 
+/// Answers a JSON body that does not fit the expected shape like any other rejected field.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> Error {
+    let JsonPayloadError::Deserialize(e) = &err else {
+        return err.into();
+    };
+
+    // serde only names the field when it is missing altogether
+    let message = e.to_string();
+    let field_error = match message.strip_prefix("missing field `").and_then(|rest| rest.split_once('`')) {
+        Some((field, _)) => FieldError::malformed(field, "is required".to_string()),
+        None => FieldError::malformed("body", message),
+    };
+
+    CoreError::InvalidFields(vec![field_error]).into()
+}
+
 #[post("/payments")]
 pub async fn process_payment(
     usecases: web::Data<UseCases>, 
     payload: web::Json<Payment>
 ) -> impl Responder {
+    let correlation_id = payload.correlation_id.clone();
+
     match usecases.process_payment.clone().execute(payload.0, true).await {
+        Ok(PaymentOutcome::InFlight) => HttpResponse::Conflict().finish(),
+        Ok(PaymentOutcome::Accepted) => {
+            let status_url = format!("/payments/{}", correlation_id);
+            HttpResponse::Accepted()
+                .insert_header((header::LOCATION, status_url.clone()))
+                .json(json!({ "statusUrl": status_url }))
+        },
         Ok(_) => HttpResponse::Ok().finish(),
+        Err(e @ CoreError::InvalidFields(_)) => {
+            tracing::info!("Rejected payment {}: {}", correlation_id, e);
+            e.error_response()
+        },
         Err(e) => {
-            tracing::error!("Payment processing failed but was queued for retry: {}", e);
-            HttpResponse::InternalServerError().finish()
+            tracing::error!("Payment processing failed and could not be queued: {}", e);
+            e.error_response()
+        },
+    }
+}
+
+#[get("/payments/{correlation_id}")]
+pub async fn get_payment(usecases: web::Data<UseCases>, path: web::Path<String>) -> impl Responder {
+    match usecases.get_payment.clone().execute(path.into_inner()).await {
+        Ok(Some(details)) => HttpResponse::Ok().json(details),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Failed to look up payment: {}", e);
+            e.error_response()
         },
     }
 }
 
 #[get("/payments-summary")]
 pub async fn get_summary(usecases: web::Data<UseCases>, query: web::Query<SummaryParams>) -> impl Responder {
-    let summary = usecases.get_summary.clone().execute(query.from.clone(), query.to.clone()).await;
+    let window = match SummaryWindow::parse(query.from.as_deref(), query.to.as_deref()) {
+        Ok(window) => window,
+        Err(e) => {
+            tracing::info!("Rejected payments summary window: {}", e);
+            return e.error_response();
+        },
+    };
 
-    HttpResponse::Ok().json(summary)
+    match usecases.get_summary.clone().execute(window).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => {
+            tracing::error!("Failed to build payments summary: {}", e);
+            e.error_response()
+        },
+    }
 }
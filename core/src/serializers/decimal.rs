@@ -1,11 +1,144 @@
-use serde::{Serializer};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde_json::Value;
 use rust_decimal::Decimal;
 use rust_decimal::prelude::ToPrimitive;
+use crate::config::MoneyFormat;
+
+// Set once at startup; amounts are exact JSON numbers until then
+static FORMAT: OnceLock<(MoneyFormat, u32)> = OnceLock::new();
+
+/// Picks how amounts are written in and read from the API, see `MoneyFormat`.
+/// `minor_unit_scale` is the number of decimal places a minor unit stands for.
+pub fn configure(format: MoneyFormat, minor_unit_scale: u32) {
+    let _ = FORMAT.set((format, minor_unit_scale));
+}
+
+fn format() -> (MoneyFormat, u32) {
+    FORMAT.get().copied().unwrap_or((MoneyFormat::Number, 2))
+}
+
+fn parse(digits: &str) -> Option<Decimal> {
+    Decimal::from_str(digits).or_else(|_| Decimal::from_scientific(digits)).ok()
+}
 
 pub fn serialize<S>(decimal: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let float_val = decimal.to_f64().unwrap_or(0.0);
-    serializer.serialize_f64(float_val)
-}
\ No newline at end of file
+    write(format(), decimal, serializer)
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    read(format(), deserializer)
+}
+
+fn write<S>(format: (MoneyFormat, u32), decimal: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match format {
+        (MoneyFormat::Number, _) => rust_decimal::serde::arbitrary_precision::serialize(decimal, serializer),
+        (MoneyFormat::String, _) => serializer.serialize_str(&decimal.to_string()),
+        (MoneyFormat::MinorUnits, scale) => {
+            // Refuses to drop digits instead of rounding them away
+            let minor_units = 10i64.checked_pow(scale)
+                .and_then(|factor| decimal.checked_mul(Decimal::from(factor)))
+                .filter(|units| units.fract().is_zero())
+                .and_then(|units| units.to_i64())
+                .ok_or_else(|| S::Error::custom(format!("{} does not fit in minor units with {} decimal places", decimal, scale)))?;
+            serializer.serialize_i64(minor_units)
+        },
+    }
+}
+
+fn read<'de, D>(format: (MoneyFormat, u32), deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+
+    let decimal = match (format, &value) {
+        // Numbers keep every digit they were sent with, so nothing goes through a float
+        ((MoneyFormat::Number, _), Value::Number(number)) => parse(&number.to_string()),
+        ((MoneyFormat::String, _), Value::String(digits)) => parse(digits),
+        ((MoneyFormat::MinorUnits, scale), Value::Number(number)) => number.as_i64().and_then(|units| Decimal::try_new(units, scale).ok()),
+        _ => None,
+    };
+
+    decimal.ok_or_else(|| {
+        let expected = match format {
+            (MoneyFormat::Number, _) => "a decimal number".to_string(),
+            (MoneyFormat::String, _) => "a string holding a decimal number".to_string(),
+            (MoneyFormat::MinorUnits, scale) => format!("an integer amount of minor units with {} decimal places", scale),
+        };
+        D::Error::custom(format!("invalid amount {}, expected {}", value, expected))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTS: u32 = 2;
+
+    fn amount(digits: &str) -> Decimal {
+        Decimal::from_str(digits).unwrap()
+    }
+
+    fn to_json(format: MoneyFormat, scale: u32, decimal: &str) -> Result<String, serde_json::Error> {
+        let mut out = Vec::new();
+        write((format, scale), &amount(decimal), &mut serde_json::Serializer::new(&mut out))?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn from_json(format: MoneyFormat, scale: u32, json: &str) -> Result<Decimal, serde_json::Error> {
+        read((format, scale), &mut serde_json::Deserializer::from_str(json))
+    }
+
+    #[test]
+    fn numbers_keep_every_digit() {
+        assert_eq!(to_json(MoneyFormat::Number, CENTS, "10.50").unwrap(), "10.50");
+        assert_eq!(to_json(MoneyFormat::Number, CENTS, "0.1").unwrap(), "0.1");
+
+        let decimal = from_json(MoneyFormat::Number, CENTS, "10.50").unwrap();
+        assert_eq!(decimal.to_string(), "10.50");
+        assert_eq!(from_json(MoneyFormat::Number, CENTS, "1e2").unwrap(), amount("100"));
+        assert!(from_json(MoneyFormat::Number, CENTS, "\"10.50\"").is_err());
+    }
+
+    #[test]
+    fn strings_hold_the_digits() {
+        assert_eq!(to_json(MoneyFormat::String, CENTS, "10.50").unwrap(), "\"10.50\"");
+
+        let decimal = from_json(MoneyFormat::String, CENTS, "\"10.50\"").unwrap();
+        assert_eq!(decimal.to_string(), "10.50");
+        assert!(from_json(MoneyFormat::String, CENTS, "10.50").is_err());
+        assert!(from_json(MoneyFormat::String, CENTS, "\"ten\"").is_err());
+    }
+
+    #[test]
+    fn minor_units_are_whole_numbers() {
+        assert_eq!(to_json(MoneyFormat::MinorUnits, CENTS, "10.50").unwrap(), "1050");
+        assert_eq!(to_json(MoneyFormat::MinorUnits, CENTS, "7").unwrap(), "700");
+        // A fraction of a cent would have to be rounded away
+        assert!(to_json(MoneyFormat::MinorUnits, CENTS, "10.505").is_err());
+
+        assert_eq!(from_json(MoneyFormat::MinorUnits, CENTS, "1050").unwrap(), amount("10.50"));
+        assert!(from_json(MoneyFormat::MinorUnits, CENTS, "10.5").is_err());
+        assert!(from_json(MoneyFormat::MinorUnits, CENTS, "\"1050\"").is_err());
+    }
+
+    #[test]
+    fn minor_units_with_too_many_decimal_places_are_refused() {
+        assert!(to_json(MoneyFormat::MinorUnits, 19, "1").is_err());
+        assert!(to_json(MoneyFormat::MinorUnits, 40, "1").is_err());
+        assert!(from_json(MoneyFormat::MinorUnits, 40, "1").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{info, warn};
+
+// Extra time given to background tasks to requeue what they could not finish
+const REQUEUE_GRACE: Duration = Duration::from_secs(2);
+
+/// Tells background tasks to stop pulling work, then waits for them to wind down.
+pub struct ShutdownCoordinator {
+    tx: watch::Sender<bool>,
+    deadline: Duration,
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+    status: TaskStatus,
+}
+
+/// Shared view of the tracked background tasks, so readiness can tell whether they still run.
+#[derive(Clone, Debug, Default)]
+pub struct TaskStatus {
+    tasks: Arc<Mutex<Vec<(&'static str, AbortHandle)>>>,
+}
+
+impl TaskStatus {
+    /// Names of the tracked tasks that are no longer running.
+    pub fn stopped(&self) -> Vec<&'static str> {
+        self.tasks.lock().unwrap().iter()
+            .filter(|(_, handle)| handle.is_finished())
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.lock().unwrap().is_empty()
+    }
+}
+
+/// Handed to each background task. `deadline` is how long the task may keep waiting
+/// for in-flight work once shutdown has been triggered.
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal {
+    rx: watch::Receiver<bool>,
+    deadline: Duration,
+}
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    pub async fn triggered(&mut self) {
+        // An error means the coordinator is gone, which is as good as a shutdown
+        let _ = self.rx.wait_for(|triggered| *triggered).await;
+    }
+
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+}
+
+impl ShutdownCoordinator {
+    pub fn new(deadline: Duration) -> Self {
+        let (tx, _) = watch::channel(false);
+
+        Self {
+            tx,
+            deadline,
+            tasks: Vec::new(),
+            status: TaskStatus::default(),
+        }
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status.clone()
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            rx: self.tx.subscribe(),
+            deadline: self.deadline,
+        }
+    }
+
+    pub fn track(&mut self, name: &'static str, handle: JoinHandle<()>) {
+        self.status.tasks.lock().unwrap().push((name, handle.abort_handle()));
+        self.tasks.push((name, handle));
+    }
+
+    pub async fn drain(self) {
+        info!("Shutting down, waiting up to {:?} for in-flight work", self.deadline);
+        self.tx.send_replace(true);
+
+        for (name, handle) in self.tasks {
+            let abort = handle.abort_handle();
+            match tokio::time::timeout(self.deadline + REQUEUE_GRACE, handle).await {
+                Ok(_) => info!("{} stopped", name),
+                Err(_) => {
+                    warn!("{} did not stop in time, aborting it", name);
+                    abort.abort();
+                }
+            }
+        }
+    }
+}
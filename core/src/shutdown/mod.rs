@@ -0,0 +1,3 @@
+mod coordinator;
+
+pub use coordinator::{ShutdownCoordinator, ShutdownSignal, TaskStatus};
@@ -0,0 +1,162 @@
+use deadpool_postgres::Pool;
+use tracing::{info, error, warn};
+use uuid::Uuid;
+use crate::config::Settings;
+use crate::errors::CoreError;
+use crate::models::PaymentOutcome;
+
+// Takes the key when it is new or when its in-flight claim has gone stale; an existing
+// row that is settled or still fresh is left alone and nothing is returned
+const CLAIM_QUERY: &str = "
+    INSERT INTO payment_requests (correlation_id, outcome, updated_at) VALUES ($1, 'in_flight', now())
+    ON CONFLICT (correlation_id) DO UPDATE SET updated_at = now()
+    WHERE payment_requests.outcome = 'in_flight'
+      AND payment_requests.updated_at < now() - make_interval(secs => $2)
+    RETURNING correlation_id";
+
+/// Remembers every correlation id `POST /payments` has accepted and what came of it.
+#[derive(Clone, Debug)]
+pub struct IdempotencyStore {
+    db_pool: Pool,
+    claim_timeout_secs: f64,
+}
+
+fn parse_key(correlation_id: &str) -> Option<Uuid> {
+    match Uuid::parse_str(correlation_id) {
+        Ok(uuid) => Some(uuid),
+        Err(e) => {
+            warn!("Cannot deduplicate correlation id '{}': {}", correlation_id, e);
+            None
+        }
+    }
+}
+
+impl IdempotencyStore {
+    pub async fn new(db_pool: Pool, settings: &Settings) -> Self {
+        Self {
+            db_pool,
+            claim_timeout_secs: settings.idempotency_claim_timeout_ms as f64 / 1000.0,
+        }
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Object, CoreError> {
+        self.db_pool.get().await.map_err(|e| {
+            error!("Failed to get Postgres connection: {}", e);
+            CoreError::Storage(format!("Failed to get Postgres connection: {}", e))
+        })
+    }
+
+    /// Marks the correlation id as in flight for this request. Returns `None` when the
+    /// caller now owns it, or the outcome recorded by the request that got there first.
+    pub async fn claim(&self, correlation_id: &str) -> Result<Option<PaymentOutcome>, CoreError> {
+        let Some(key) = parse_key(correlation_id) else {
+            return Ok(None);
+        };
+
+        let client = self.client().await?;
+
+        let claimed = client
+            .query_opt(CLAIM_QUERY, &[&key, &self.claim_timeout_secs])
+            .await
+            .map_err(|e| CoreError::Storage(format!("Failed to claim correlation id {}: {}", key, e)))?;
+
+        if claimed.is_some() {
+            return Ok(None);
+        }
+
+        let row = client
+            .query_opt("SELECT outcome FROM payment_requests WHERE correlation_id = $1", &[&key])
+            .await
+            .map_err(|e| CoreError::Storage(format!("Failed to read outcome for {}: {}", key, e)))?;
+
+        // The first request released its claim in between; the caller can try again
+        let outcome = match row {
+            Some(row) => PaymentOutcome::from_str(row.get(0)).unwrap_or(PaymentOutcome::InFlight),
+            None => PaymentOutcome::InFlight,
+        };
+
+        info!("Correlation id {} already seen ({})", key, outcome);
+        Ok(Some(outcome))
+    }
+
+    pub async fn complete(&self, correlation_id: &str, outcome: PaymentOutcome) -> Result<(), CoreError> {
+        let Some(key) = parse_key(correlation_id) else {
+            return Ok(());
+        };
+
+        self.client().await?
+            .execute(
+                "UPDATE payment_requests SET outcome = $2, updated_at = now() WHERE correlation_id = $1",
+                &[&key, &outcome.to_string()],
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| CoreError::Storage(format!("Failed to record outcome for {}: {}", key, e)))
+    }
+
+    /// Gives up an in-flight claim so a retried request is processed from scratch.
+    pub async fn release(&self, correlation_id: &str) -> Result<(), CoreError> {
+        let Some(key) = parse_key(correlation_id) else {
+            return Ok(());
+        };
+
+        self.client().await?
+            .execute(
+                "DELETE FROM payment_requests WHERE correlation_id = $1 AND outcome = 'in_flight'",
+                &[&key],
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| CoreError::Storage(format!("Failed to release correlation id {}: {}", key, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+    use crate::store::create_pool;
+
+    async fn store() -> IdempotencyStore {
+        let settings = Settings::for_tests(json!({ "idempotency_claim_timeout_ms": 60_000 }));
+        IdempotencyStore::new(create_pool(&settings).await, &settings).await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres on localhost with init-db/init-db.sql applied"]
+    async fn the_first_claim_owns_the_correlation_id() {
+        let store = store().await;
+        let correlation_id = Uuid::new_v4().to_string();
+
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres on localhost with init-db/init-db.sql applied"]
+    async fn a_duplicate_while_in_flight_is_told_so() {
+        let store = store().await;
+        let correlation_id = Uuid::new_v4().to_string();
+
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), None);
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), Some(PaymentOutcome::InFlight));
+
+        // Released, so the next request takes it over
+        store.release(&correlation_id).await.unwrap();
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres on localhost with init-db/init-db.sql applied"]
+    async fn a_duplicate_after_completion_gets_the_recorded_outcome() {
+        let store = store().await;
+        let correlation_id = Uuid::new_v4().to_string();
+
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), None);
+        store.complete(&correlation_id, PaymentOutcome::Processed).await.unwrap();
+
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), Some(PaymentOutcome::Processed));
+        // Releasing only gives up claims still in flight
+        store.release(&correlation_id).await.unwrap();
+        assert_eq!(store.claim(&correlation_id).await.unwrap(), Some(PaymentOutcome::Processed));
+    }
+}
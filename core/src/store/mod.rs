@@ -1,3 +1,7 @@
+mod idempotency;
 mod payment;
+mod pool;
 
-pub use payment::{PaymentStore};
\ No newline at end of file
+pub use idempotency::{IdempotencyStore};
+pub use payment::{PaymentStore};
+pub use pool::{create_pool};
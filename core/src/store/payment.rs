@@ -1,131 +1,239 @@
-use deadpool_postgres::{Config, Pool, Runtime};
-use tokio_postgres::NoTls;
-use crate::config::Settings;
-use crate::models::{Payment, PaymentMetric, PaymentProcessorName, PaymentSummary};
+use std::collections::BTreeMap;
+use deadpool_postgres::Pool;
+use crate::errors::CoreError;
+use crate::metrics::Metrics;
+use crate::models::{Payment, PaymentDetails, PaymentMetric, PaymentStatus, PaymentSummary, SummaryWindow};
 use uuid::Uuid;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use tracing::error;
+
+// Every statement that changes a payment's status returns the rows it touched, plus a
+// `detail` column, as `changed`. Those statements only match rows whose status actually
+// changes, so the history only grows when a transition happened
+const RECORD_HISTORY: &str = "
+    INSERT INTO payment_status_history (correlation_id, status, retry_count, detail)
+    SELECT correlation_id, status, retry_count, detail FROM changed";
+
+fn status_timestamp_column(status: PaymentStatus) -> &'static str {
+    match status {
+        PaymentStatus::Received => "received_at",
+        PaymentStatus::Processing => "processing_at",
+        PaymentStatus::Succeeded => "succeeded_at",
+        PaymentStatus::Retrying => "retrying_at",
+        PaymentStatus::DeadLettered => "dead_lettered_at",
+        PaymentStatus::Failed => "failed_at",
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct PaymentStore {
-    db_pool: Pool
+    db_pool: Pool,
+    metrics: Metrics,
 }
 
 impl PaymentStore {
-    pub async fn new(settings: Settings) -> Self {
-        let mut db_config = Config::new();
-        db_config.host = Some(settings.db_host);
-        db_config.port = Some(settings.db_port);
-        db_config.dbname = Some(settings.db_name);
-        db_config.user = Some(settings.db_user);
-        db_config.password = Some(settings.db_password);
+    pub async fn new(db_pool: Pool, metrics: Metrics) -> Self {
+        Self {
+            db_pool,
+            metrics,
+        }
+    }
 
-        let pool = db_config.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+    async fn client(&self) -> Result<deadpool_postgres::Object, CoreError> {
+        let client = self.db_pool.get().await
+            .map_err(|e| CoreError::Storage(format!("Failed to get Postgres connection: {}", e)));
+        self.record_pool_status();
+        client
+    }
 
-        _ = pool.get().await.unwrap();
+    /// Copies the pool's current size and idle connections into the metrics.
+    pub fn record_pool_status(&self) {
+        self.metrics.set_db_pool(self.db_pool.status());
+    }
 
-        Self {
-            db_pool: pool
-        }
+    /// Checks a connection out of the pool and runs a trivial query on it.
+    pub async fn ping(&self) -> Result<(), CoreError> {
+        self.client().await?
+            .simple_query("SELECT 1")
+            .await
+            .map(|_| ())
+            .map_err(|e| CoreError::Storage(format!("Failed to query Postgres: {}", e)))
     }
 
-    pub async fn create_payment(&self, payment: Payment, payment_processor_name: String) {
-        let client = self.db_pool.get().await.unwrap();
+    pub async fn is_succeeded(&self, correlation_id: &str) -> Result<bool, CoreError> {
+        let Ok(correlation_id) = Uuid::parse_str(correlation_id) else {
+            return Ok(false);
+        };
 
-        let payment_processor = match payment_processor_name.as_str() {
-            "default" => PaymentProcessorName::Default.to_string(),
-            "fallback" => PaymentProcessorName::Fallback.to_string(),
-            _ => PaymentProcessorName::Default.to_string(),
+        self.client().await?
+            .query_opt(
+                "SELECT 1 FROM payments WHERE correlation_id = $1 AND status = 'succeeded'",
+                &[&correlation_id],
+            )
+            .await
+            .map(|row| row.is_some())
+            .map_err(|e| CoreError::Storage(format!("Failed to look up payment {}: {}", correlation_id, e)))
+    }
+
+    pub async fn find_payment(&self, correlation_id: &str) -> Result<Option<PaymentDetails>, CoreError> {
+        let Ok(key) = Uuid::parse_str(correlation_id) else {
+            return Ok(None);
         };
 
-        let correlation_id = match Uuid::parse_str(&payment.correlation_id) {
-            Ok(uuid) => uuid,
-            Err(e) => {
-                tracing::error!("Failed to parse UUID '{}': {}", payment.correlation_id, e);
-                Uuid::new_v4()
-            }
+        let row = self.client().await?
+            .query_opt(
+                "SELECT amount, payment_processor, requested_at, status, retry_count
+                 FROM payments WHERE correlation_id = $1",
+                &[&key],
+            )
+            .await
+            .map_err(|e| CoreError::Storage(format!("Failed to look up payment {}: {}", key, e)))?;
+
+        let Some(row) = row else {
+            return Ok(None);
         };
 
-        let requested_at = match DateTime::parse_from_rfc3339(&payment.requested_at) {
-            Ok(dt) => dt.with_timezone(&Utc),
-            Err(e) => {
-                tracing::error!("Failed to parse timestamp '{}': {}", payment.requested_at, e);
-                Utc::now()
-            }
+        let requested_at: NaiveDateTime = row.get(2);
+        let status: String = row.get(3);
+        let retry_count: i16 = row.get(4);
+
+        Ok(Some(PaymentDetails {
+            correlation_id: key.to_string(),
+            amount: row.get(0),
+            payment_processor: row.get(1),
+            requested_at: requested_at.and_utc().to_rfc3339(),
+            status: PaymentStatus::from_str(&status)
+                .ok_or_else(|| CoreError::Storage(format!("Unknown status '{}' for payment {}", status, key)))?,
+            retry_count: retry_count.try_into().unwrap_or(u8::MAX),
+        }))
+    }
+
+    /// Records a payment accepted by `POST /payments`. A payment that failed before
+    /// starts over, anything else already on file is left as it is.
+    pub async fn receive_payment(&self, payment: &Payment) -> Result<(), CoreError> {
+        let Ok(correlation_id) = Uuid::parse_str(&payment.correlation_id) else {
+            return Ok(());
         };
 
+        let query = format!(
+            "WITH changed AS (
+                INSERT INTO payments (correlation_id, amount, requested_at, status, retry_count, received_at, updated_at)
+                VALUES ($1, $2, $3, '{status}', 0, now(), now())
+                ON CONFLICT (correlation_id) DO UPDATE
+                SET amount = EXCLUDED.amount, requested_at = EXCLUDED.requested_at, status = '{status}',
+                    retry_count = 0, received_at = now(), updated_at = now()
+                WHERE payments.status = '{failed}'
+                RETURNING correlation_id, status, retry_count, NULL::text AS detail
+            ){RECORD_HISTORY}",
+            status = PaymentStatus::Received,
+            failed = PaymentStatus::Failed,
+        );
+
+        self.client().await?
+            .execute(&query, &[&correlation_id, &payment.amount, &Utc::now().naive_utc()])
+            .await
+            .map(|_| ())
+            .map_err(|e| CoreError::Storage(format!("Failed to record payment {}: {}", correlation_id, e)))
+    }
+
+    /// Moves a recorded payment to `status` when its current status is one of
+    /// `PaymentStatus::allowed_from`; any other payment, including one already in `status`,
+    /// is left as it is. `retry_count` is only overwritten when given.
+    pub async fn transition(
+        &self,
+        correlation_id: &str,
+        status: PaymentStatus,
+        retry_count: Option<u8>,
+        detail: Option<String>,
+    ) -> Result<(), CoreError> {
+        let Ok(correlation_id) = Uuid::parse_str(correlation_id) else {
+            return Ok(());
+        };
+
+        let query = format!(
+            "WITH changed AS (
+                UPDATE payments
+                SET status = $2, retry_count = COALESCE($3, retry_count), {} = now(), updated_at = now()
+                WHERE correlation_id = $1 AND status <> $2 AND status = ANY($5)
+                RETURNING correlation_id, status, retry_count, $4::text AS detail
+            ){}",
+            status_timestamp_column(status),
+            RECORD_HISTORY,
+        );
+        let retry_count = retry_count.map(i16::from);
+        let allowed_from: Vec<String> = status.allowed_from().iter().map(ToString::to_string).collect();
+
+        self.client().await?
+            .execute(&query, &[&correlation_id, &status.to_string(), &retry_count, &detail, &allowed_from])
+            .await
+            .map(|_| ())
+            .map_err(|e| CoreError::Storage(format!("Failed to move payment {} to {}: {}", correlation_id, status, e)))
+    }
+
+    pub async fn create_payment(&self, payment: Payment, payment_processor: String) -> Result<(), CoreError> {
+        // Validated on the way in, so only a message queued before that could still get here
+        let correlation_id = Uuid::parse_str(&payment.correlation_id)
+            .map_err(|e| CoreError::Validation(format!("Invalid correlation id '{}': {}", payment.correlation_id, e)))?;
+
+        // Recording another time instead would move the payment to the wrong summary window
+        let requested_at = DateTime::parse_from_rfc3339(&payment.requested_at)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|e| CoreError::Validation(format!("Invalid requestedAt '{}': {}", payment.requested_at, e)))?;
+
+        let client = self.client().await?;
+
         // Convert DateTime<Utc> to NaiveDateTime for PostgreSQL compatibility
         let naive_requested_at = requested_at.naive_utc();
 
+        // Payments queued before they were ever recorded get their row here
+        let query = format!(
+            "WITH changed AS (
+                INSERT INTO payments (correlation_id, payment_processor, amount, requested_at, status, succeeded_at, updated_at)
+                VALUES ($1, $2, $3, $4, '{status}', now(), now())
+                ON CONFLICT (correlation_id) DO UPDATE
+                SET payment_processor = EXCLUDED.payment_processor, amount = EXCLUDED.amount,
+                    requested_at = EXCLUDED.requested_at, status = '{status}', succeeded_at = now(), updated_at = now()
+                WHERE payments.status <> '{status}'
+                RETURNING correlation_id, status, retry_count, NULL::text AS detail
+            ){RECORD_HISTORY}",
+            status = PaymentStatus::Succeeded,
+        );
+
         match client.execute(
-            "INSERT INTO payments (correlation_id, payment_processor, amount, requested_at) VALUES ($1, $2, $3, $4)",
+            &query,
             &[&correlation_id, &payment_processor, &payment.amount, &naive_requested_at],
         ).await {
-            Ok(_) => tracing::info!("Payment inserted successfully"),
-            Err(e) => tracing::error!("Failed to insert payment: {}", e),
-        };
+            Ok(0) => {
+                tracing::info!("Payment {} was already recorded", correlation_id);
+                Ok(())
+            },
+            Ok(_) => {
+                tracing::info!("Payment inserted successfully");
+                Ok(())
+            },
+            Err(e) => {
+                tracing::error!("Failed to insert payment: {}", e);
+                Err(CoreError::Storage(format!("Failed to insert payment {}: {}", correlation_id, e)))
+            },
+        }
     }
 
-    fn build_metrics_query(&self, from: &Option<String>, to: &Option<String>) -> (String, Vec<chrono::NaiveDateTime>) {
+    fn build_metrics_query(&self, window: &SummaryWindow) -> (String, Vec<NaiveDateTime>) {
         let mut query = String::from(
             "SELECT payment_processor, COUNT(1) as count, SUM(amount) as total_amount
-             FROM payments"
+             FROM payments
+             WHERE status = 'succeeded'"
         );
 
         let mut params = Vec::new();
-        let mut param_index = 1;
-
-        // Process from date if provided
-        let mut from_parsed = None;
-        if let Some(from_date) = from {
-            let parsed = DateTime::parse_from_rfc3339(&from_date)
-                .map(|dt| dt.naive_utc())
-                .or_else(|_| NaiveDateTime::parse_from_str(&from_date, "%Y-%m-%dT%H:%M:%S"));
-
-            match parsed {
-                Ok(dt) => {
-                    from_parsed = Some(dt);
-                }
-                Err(e) => {
-                    error!("Failed to parse date `{}`: {}", from_date, e);
-                }
-            }
-        }
 
-        // Process to date if provided
-        let mut to_parsed = None;
-        if let Some(to_date) = to {
-            let parsed = DateTime::parse_from_rfc3339(&to_date)
-                .map(|dt| dt.naive_utc())
-                .or_else(|_| NaiveDateTime::parse_from_str(&to_date, "%Y-%m-%dT%H:%M:%S"));
-
-            match parsed {
-                Ok(dt) => {
-                    to_parsed = Some(dt);
-                }
-                Err(e) => {
-                    error!("Failed to parse date `{}`: {}", to_date, e);
-                }
-            }
+        if let Some(from) = window.from {
+            params.push(from.naive_utc());
+            query.push_str(&format!(" AND requested_at >= ${}", params.len()));
         }
 
-        // Add WHERE clause only if we have at least one valid date
-        if from_parsed.is_some() || to_parsed.is_some() {
-            query.push_str(" WHERE");
-
-            if let Some(parsed_date) = from_parsed {
-                query.push_str(&format!(" requested_at >= ${}", param_index));
-                params.push(parsed_date);
-                param_index += 1;
-            }
-
-            if let Some(parsed_date) = to_parsed {
-                if param_index > 1 {
-                    query.push_str(" AND");
-                }
-                query.push_str(&format!(" requested_at <= ${}", param_index));
-                params.push(parsed_date);
-            }
+        if let Some(to) = window.to {
+            params.push(to.naive_utc());
+            query.push_str(&format!(" AND requested_at <= ${}", params.len()));
         }
 
         query.push_str(" GROUP BY payment_processor");
@@ -133,34 +241,30 @@ impl PaymentStore {
         (query, params)
     }
 
-    fn process_metrics_results(&self, rows: Vec<tokio_postgres::Row>) -> PaymentSummary {
-        let mut default_metric: PaymentMetric = PaymentMetric { total_requests: 0, total_amount: Default::default() };
-        let mut fallback_metric: PaymentMetric = PaymentMetric { total_requests: 0, total_amount: Default::default() };
+    fn process_metrics_results(&self, rows: Vec<tokio_postgres::Row>, window: SummaryWindow) -> PaymentSummary {
+        let mut processors = BTreeMap::new();
 
         for row in rows {
             let payment_processor: String = row.get(0);
+            let requests: i64 = row.get(1);
 
-            if payment_processor == PaymentProcessorName::Default.to_string() {
-                let requests: i64 = row.get(1);
-                default_metric.total_requests = requests.try_into().unwrap();
-                default_metric.total_amount = row.get(2);
-            } else {
-                let requests: i64 = row.get(1);
-                fallback_metric.total_requests = requests.try_into().unwrap();
-                fallback_metric.total_amount = row.get(2);
-            }
+            processors.insert(payment_processor, PaymentMetric {
+                total_requests: requests.try_into().unwrap_or_default(),
+                total_amount: row.get(2),
+            });
         }
 
-        PaymentSummary{ default: default_metric, fallback: fallback_metric }
+        PaymentSummary{ processors, window }
     }
 
-    pub async fn get_metrics(&self, from: Option<String>, to: Option<String>) -> PaymentSummary {
-        let client = self.db_pool.get().await.unwrap();
+    pub async fn get_metrics(&self, window: SummaryWindow) -> Result<PaymentSummary, CoreError> {
+        let client = self.client().await?;
 
-        let (query, params) = self.build_metrics_query(&from, &to);
+        let (query, params) = self.build_metrics_query(&window);
 
-        let rows = client.query(&query, &params.iter().map(|p| p as &(dyn tokio_postgres::types::ToSql + Sync)).collect::<Vec<_>>()).await.unwrap();
+        let rows = client.query(&query, &params.iter().map(|p| p as &(dyn tokio_postgres::types::ToSql + Sync)).collect::<Vec<_>>()).await
+            .map_err(|e| CoreError::Storage(format!("Failed to query payment metrics: {}", e)))?;
 
-        self.process_metrics_results(rows)
+        Ok(self.process_metrics_results(rows, window))
     }
 }
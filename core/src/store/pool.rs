@@ -0,0 +1,18 @@
+use deadpool_postgres::{Config, Pool, Runtime};
+use tokio_postgres::NoTls;
+use crate::config::Settings;
+
+pub async fn create_pool(settings: &Settings) -> Pool {
+    let mut db_config = Config::new();
+    db_config.host = Some(settings.db_host.clone());
+    db_config.port = Some(settings.db_port);
+    db_config.dbname = Some(settings.db_name.clone());
+    db_config.user = Some(settings.db_user.clone());
+    db_config.password = Some(settings.db_password.clone());
+
+    let pool = db_config.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+
+    _ = pool.get().await.unwrap();
+
+    pool
+}
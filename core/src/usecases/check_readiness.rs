@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use redis::Client;
+use redis::aio::ConnectionManager;
+use tokio::sync::OnceCell;
+use tracing::warn;
+use crate::config::{QueueBackendKind, Settings};
+use crate::errors::CoreError;
+use crate::models::{DependencyHealth, Readiness};
+use crate::shutdown::TaskStatus;
+use crate::store::PaymentStore;
+
+#[derive(Clone)]
+pub struct CheckReadiness {
+    payment_store: PaymentStore,
+    // Whether the queue or the shared processor health lives in Redis
+    uses_redis: bool,
+    // Unset when Redis is not used or its URL is invalid
+    redis: Option<Client>,
+    // Opened by the first probe and kept, so probes do not each open a connection; it
+    // reconnects on its own when Redis goes away
+    redis_connection: Arc<OnceCell<ConnectionManager>>,
+    tasks: TaskStatus,
+    timeout: Duration,
+}
+
+impl std::fmt::Debug for CheckReadiness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CheckReadiness")
+            .field("uses_redis", &self.uses_redis)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+// Runs one check, turning an error or a check that takes too long into a down dependency
+async fn check(timeout: Duration, probe: impl Future<Output = Result<(), CoreError>>) -> DependencyHealth {
+    match tokio::time::timeout(timeout, probe).await {
+        Ok(Ok(())) => DependencyHealth::up(),
+        Ok(Err(e)) => DependencyHealth::down(e.to_string()),
+        Err(_) => DependencyHealth::down(format!("no answer within {:?}", timeout)),
+    }
+}
+
+impl CheckReadiness {
+    pub async fn new(payment_store: PaymentStore, tasks: TaskStatus, settings: &Settings) -> Self {
+        let uses_redis = settings.queue_backend == QueueBackendKind::Redis || settings.processor_health_shared;
+        let redis = if uses_redis {
+            Client::open(settings.redis_url.clone())
+                .inspect_err(|e| warn!("Invalid Redis URL, readiness will report Redis as down: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        Self {
+            payment_store,
+            uses_redis,
+            redis,
+            redis_connection: Arc::new(OnceCell::new()),
+            tasks,
+            timeout: Duration::from_millis(settings.health_check_timeout_ms),
+        }
+    }
+
+    pub async fn execute(self) -> Readiness {
+        let mut checks = BTreeMap::new();
+
+        checks.insert("postgres".to_string(), check(self.timeout, self.payment_store.ping()).await);
+
+        let redis = match (&self.redis, self.uses_redis) {
+            (Some(client), _) => check(self.timeout, ping_redis(client, &self.redis_connection)).await,
+            (None, true) => DependencyHealth::down("invalid Redis URL".to_string()),
+            (None, false) => DependencyHealth::disabled(),
+        };
+        checks.insert("redis".to_string(), redis);
+
+        let stopped = self.tasks.stopped();
+        let consumers = if self.tasks.is_empty() {
+            DependencyHealth::down("not started".to_string())
+        } else if !stopped.is_empty() {
+            DependencyHealth::down(format!("stopped: {}", stopped.join(", ")))
+        } else {
+            DependencyHealth::up()
+        };
+        checks.insert("consumers".to_string(), consumers);
+
+        Readiness::new(checks)
+    }
+}
+
+async fn ping_redis(client: &Client, connection: &OnceCell<ConnectionManager>) -> Result<(), CoreError> {
+    let mut conn = connection
+        .get_or_try_init(|| ConnectionManager::new(client.clone()))
+        .await
+        .map_err(|e| CoreError::Queue(format!("Failed to connect to Redis: {}", e)))?
+        .clone();
+
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut conn)
+        .await
+        .map(|_| ())
+        .map_err(|e| CoreError::Queue(format!("Failed to ping Redis: {}", e)))
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use chrono::DateTime;
+use tracing::info;
+use crate::errors::CoreError;
+use crate::models::{DeadLetterEntry, DeadLetterPage};
+use crate::queue::{Delivery, MessageWrapper, QueueBackend, QueueKind};
+
+// Page size used when walking the whole DLQ
+const BATCH_SIZE: usize = 100;
+
+#[derive(Clone, Debug)]
+pub struct DeadLetters {
+    backend: Arc<dyn QueueBackend>,
+}
+
+fn to_entry(delivery: Delivery) -> DeadLetterEntry {
+    let wrapper = delivery.wrapper;
+
+    DeadLetterEntry {
+        id: delivery.id,
+        retry_count: wrapper.retry_count,
+        first_failed_at: wrapper.first_failed_at
+            .and_then(DateTime::from_timestamp_millis)
+            .map(|at| at.to_rfc3339()),
+        last_error: wrapper.last_error,
+        message: serde_json::from_str(&wrapper.message)
+            .unwrap_or(serde_json::Value::String(wrapper.message)),
+    }
+}
+
+impl DeadLetters {
+    pub async fn new(backend: Arc<dyn QueueBackend>) -> Self {
+        Self {
+            backend
+        }
+    }
+
+    pub async fn list(&self, after: Option<String>, limit: usize) -> Result<DeadLetterPage, CoreError> {
+        let total = self.backend.len(QueueKind::DeadLetter).await?;
+        let deliveries = self.backend.list(QueueKind::DeadLetter, after, limit).await?;
+
+        let next_cursor = match deliveries.last() {
+            Some(last) if deliveries.len() == limit => Some(last.id.clone()),
+            _ => None,
+        };
+
+        Ok(DeadLetterPage {
+            entries: deliveries.into_iter().map(to_entry).collect(),
+            next_cursor,
+            total,
+        })
+    }
+
+    pub async fn get(&self, id: String) -> Result<Option<DeadLetterEntry>, CoreError> {
+        let delivery = self.backend.get(QueueKind::DeadLetter, &id).await?;
+        Ok(delivery.map(to_entry))
+    }
+
+    /// Moves one entry back to the main queue with a fresh retry budget.
+    /// Returns `false` when the entry is no longer in the DLQ or the DLQ consumer holds it.
+    pub async fn replay(&self, id: String) -> Result<bool, CoreError> {
+        let reset = |wrapper: &mut MessageWrapper| {
+            wrapper.retry_count = 0;
+            wrapper.next_attempt_at = None;
+        };
+
+        let replayed = self.backend.move_to(QueueKind::DeadLetter, QueueKind::Main, &id, &reset).await?;
+        if replayed {
+            info!("Replayed DLQ entry {}", id);
+        }
+        Ok(replayed)
+    }
+
+    /// Moves one entry to the quarantine, where nothing consumes it.
+    pub async fn quarantine(&self, id: String) -> Result<bool, CoreError> {
+        let quarantined = self.backend.move_to(QueueKind::DeadLetter, QueueKind::Quarantine, &id, &|_| {}).await?;
+        if quarantined {
+            info!("Quarantined DLQ entry {}", id);
+        }
+        Ok(quarantined)
+    }
+
+    pub async fn replay_all(&self) -> Result<usize, CoreError> {
+        let mut replayed = 0;
+        for id in self.snapshot_ids().await? {
+            if self.replay(id).await? {
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    pub async fn quarantine_all(&self) -> Result<usize, CoreError> {
+        let mut quarantined = 0;
+        for id in self.snapshot_ids().await? {
+            if self.quarantine(id).await? {
+                quarantined += 1;
+            }
+        }
+        Ok(quarantined)
+    }
+
+    pub async fn purge(&self) -> Result<usize, CoreError> {
+        let purged = self.backend.purge(QueueKind::DeadLetter).await?;
+        info!("Purged {} DLQ entries", purged);
+        Ok(purged)
+    }
+
+    // Collects the ids up front, so replayed messages that fail again and land back
+    // in the DLQ are not picked up a second time by the same bulk operation
+    async fn snapshot_ids(&self) -> Result<Vec<String>, CoreError> {
+        let mut ids = Vec::new();
+        let mut after = None;
+
+        loop {
+            let page = self.backend.list(QueueKind::DeadLetter, after, BATCH_SIZE).await?;
+            let full = page.len() == BATCH_SIZE;
+            ids.extend(page.into_iter().map(|delivery| delivery.id));
+
+            if !full {
+                return Ok(ids);
+            }
+            after = ids.last().cloned();
+        }
+    }
+}
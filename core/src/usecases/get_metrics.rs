@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use tracing::error;
+use crate::errors::CoreError;
+use crate::metrics::Metrics;
+use crate::outbound::PaymentProcessor;
+use crate::queue::{QueueBackend, QueueKind};
+use crate::store::PaymentStore;
+
+#[derive(Clone, Debug)]
+pub struct GetMetrics {
+    metrics: Metrics,
+    backend: Arc<dyn QueueBackend>,
+    payment_store: PaymentStore,
+    payment_processor: PaymentProcessor,
+}
+
+impl GetMetrics {
+    pub async fn new(
+        metrics: Metrics,
+        backend: Arc<dyn QueueBackend>,
+        payment_store: PaymentStore,
+        payment_processor: PaymentProcessor,
+    ) -> Self {
+        Self {
+            metrics,
+            backend,
+            payment_store,
+            payment_processor,
+        }
+    }
+
+    /// Refreshes the gauges that are only read on a scrape, then renders every metric.
+    pub async fn execute(self) -> Result<String, CoreError> {
+        for queue in [QueueKind::Main, QueueKind::DeadLetter, QueueKind::Quarantine] {
+            match self.backend.len(queue).await {
+                Ok(depth) => self.metrics.set_queue_depth(queue, depth),
+                // The previous depth stays until the queue answers again
+                Err(e) => error!("Failed to read {:?} queue depth: {}", queue, e),
+            }
+        }
+
+        for (name, failing) in self.payment_processor.processor_health() {
+            self.metrics.set_processor_failing(&name, failing);
+        }
+
+        self.payment_store.record_pool_status();
+
+        self.metrics.encode()
+    }
+}
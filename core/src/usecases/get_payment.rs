@@ -0,0 +1,22 @@
+use crate::errors::CoreError;
+use crate::models::PaymentDetails;
+use crate::store::PaymentStore;
+
+#[derive(Clone, Debug)]
+pub struct GetPayment {
+    payment_store: PaymentStore,
+}
+
+impl GetPayment {
+    pub async fn new(payment_store: PaymentStore) -> Self {
+        Self {
+            payment_store,
+        }
+    }
+
+    /// Every payment `POST /payments` took has a row from the moment it was received,
+    /// queued or not, so the store alone answers.
+    pub async fn execute(self, correlation_id: String) -> Result<Option<PaymentDetails>, CoreError> {
+        self.payment_store.find_payment(&correlation_id).await
+    }
+}
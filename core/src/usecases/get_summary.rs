@@ -1,21 +1,32 @@
-use crate::models::{PaymentSummary};
+use crate::errors::CoreError;
+use crate::models::{PaymentMetric, PaymentSummary, SummaryWindow};
 use crate::store::PaymentStore;
 
 #[derive(Clone, Debug)]
 pub struct GetSummary {
     payment_store: PaymentStore,
+    // Reported even before any payment went through them
+    processor_names: Vec<String>,
 }
 
 impl GetSummary {
     pub async fn new(
         payment_store: PaymentStore,
+        processor_names: Vec<String>,
     ) -> Self {
         Self {
-            payment_store
+            payment_store,
+            processor_names,
         }
     }
 
-    pub async fn execute(self, from: Option<String>, to: Option<String>) -> PaymentSummary {
-        self.payment_store.get_metrics(from, to).await
+    pub async fn execute(self, window: SummaryWindow) -> Result<PaymentSummary, CoreError> {
+        let mut summary = self.payment_store.get_metrics(window).await?;
+
+        for name in self.processor_names {
+            summary.processors.entry(name).or_insert_with(PaymentMetric::empty);
+        }
+
+        Ok(summary)
     }
 }
@@ -1,27 +1,53 @@
 mod process_payment;
 mod get_summary;
+mod get_payment;
+mod dead_letters;
+mod get_metrics;
+mod check_readiness;
 
+use std::sync::Arc;
 use process_payment::{ProcessPayment};
-use crate::queue::{Producer};
+use crate::config::Settings;
+use crate::metrics::Metrics;
+use crate::queue::{Producer, QueueBackend};
+use crate::shutdown::TaskStatus;
 use crate::outbound::PaymentProcessor;
-use crate::store::PaymentStore;
+use crate::store::{IdempotencyStore, PaymentStore};
 use crate::usecases::get_summary::GetSummary;
+use crate::usecases::get_payment::GetPayment;
+use crate::usecases::dead_letters::DeadLetters;
+use crate::usecases::get_metrics::GetMetrics;
+use crate::usecases::check_readiness::CheckReadiness;
 
 #[derive(Clone, Debug)]
 pub struct UseCases {
     pub process_payment: ProcessPayment,
     pub get_summary: GetSummary,
+    pub get_payment: GetPayment,
+    pub dead_letters: DeadLetters,
+    pub get_metrics: GetMetrics,
+    pub check_readiness: CheckReadiness,
 }
 
 impl UseCases {
     pub async fn new(
-        producer: Producer,
         payment_processor: PaymentProcessor,
         payment_store: PaymentStore,
+        idempotency_store: IdempotencyStore,
+        queue_backend: Arc<dyn QueueBackend>,
+        metrics: Metrics,
+        tasks: TaskStatus,
+        settings: &Settings,
     ) -> Self {
+        let producer = Producer::new(queue_backend.clone()).await;
+
         Self{
-            process_payment: ProcessPayment::new(producer, payment_processor, payment_store.clone()).await,
-            get_summary: GetSummary::new(payment_store).await,
+            process_payment: ProcessPayment::new(producer, payment_processor.clone(), payment_store.clone(), idempotency_store, metrics.clone(), settings).await,
+            get_summary: GetSummary::new(payment_store.clone(), payment_processor.processor_names()).await,
+            get_payment: GetPayment::new(payment_store.clone()).await,
+            dead_letters: DeadLetters::new(queue_backend.clone()).await,
+            get_metrics: GetMetrics::new(metrics, queue_backend, payment_store.clone(), payment_processor).await,
+            check_readiness: CheckReadiness::new(payment_store, tasks, settings).await,
         }
     }
 }
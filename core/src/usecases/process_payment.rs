@@ -1,15 +1,24 @@
 use chrono::Utc;
-use tracing::error;
+use rust_decimal::Decimal;
+use tracing::{info, error};
+use crate::config::{IngestionMode, Settings};
+use crate::errors::CoreError;
+use crate::metrics::Metrics;
 use crate::queue::{Producer};
-use crate::models::{Payment};
+use crate::models::{Payment, PaymentOutcome, PaymentStatus, QueuedPayment};
 use crate::outbound::PaymentProcessor;
-use crate::store::PaymentStore;
+use crate::store::{IdempotencyStore, PaymentStore};
 
 #[derive(Clone, Debug)]
 pub struct ProcessPayment {
     producer: Producer,
     payment_processor: PaymentProcessor,
     payment_store: PaymentStore,
+    idempotency_store: IdempotencyStore,
+    ingestion_mode: IngestionMode,
+    max_amount: Decimal,
+    max_scale: u32,
+    metrics: Metrics,
 }
 
 impl ProcessPayment {
@@ -17,34 +26,122 @@ impl ProcessPayment {
         producer: Producer,
         payment_processor: PaymentProcessor,
         payment_store: PaymentStore,
+        idempotency_store: IdempotencyStore,
+        metrics: Metrics,
+        settings: &Settings,
     ) -> Self {
         Self {
             producer,
             payment_processor,
-            payment_store
+            payment_store,
+            idempotency_store,
+            ingestion_mode: settings.ingestion_mode,
+            max_amount: settings.payment_max_amount,
+            max_scale: settings.payment_max_scale,
+            metrics,
         }
     }
 
-    pub async fn execute(self, mut payment: Payment, publish_on_failure: bool) -> Result<(), String>{
+    pub async fn execute(self, payment: Payment, publish_on_failure: bool) -> Result<PaymentOutcome, CoreError> {
+        // Messages from the queue were already claimed by the request that published them
+        if !publish_on_failure {
+            return self.process(payment, false).await;
+        }
+
+        // Nothing is claimed, recorded or queued for a payment that was rejected
+        if let Err(e) = payment.validate(self.max_amount, self.max_scale) {
+            self.metrics.observe_payment("invalid");
+            return Err(e);
+        }
+
+        let correlation_id = payment.correlation_id.clone();
+
+        if let Some(outcome) = self.idempotency_store.claim(&correlation_id).await? {
+            self.metrics.observe_payment(&outcome.to_string());
+            return Ok(outcome);
+        }
+
+        // `GET /payments/{id}` only reads this row, so a payment is not taken without it
+        let result = match self.payment_store.receive_payment(&payment).await {
+            Err(e) => {
+                error!("Failed to record received payment: {}", e);
+                Err(e)
+            },
+            Ok(_) => match self.ingestion_mode {
+                IngestionMode::Sync => self.clone().process(payment, true).await,
+                IngestionMode::Async => self.enqueue(payment).await.map(|_| PaymentOutcome::Accepted),
+            },
+        };
+
+        let settled = match &result {
+            Ok(outcome) => self.idempotency_store.complete(&correlation_id, *outcome).await,
+            Err(_) => self.idempotency_store.release(&correlation_id).await,
+        };
+        if let Err(e) = settled {
+            error!("Failed to settle idempotency key: {}", e);
+        }
+
+        match &result {
+            Ok(outcome) => self.metrics.observe_payment(&outcome.to_string()),
+            Err(_) => self.metrics.observe_payment("failed"),
+        }
+
+        result
+    }
+
+    async fn process(self, mut payment: Payment, publish_on_failure: bool) -> Result<PaymentOutcome, CoreError> {
+        // A retry or a redelivery may follow an attempt that was charged and recorded already
+        if self.payment_store.is_succeeded(&payment.correlation_id).await? {
+            info!("Payment {} already recorded, skipping", payment.correlation_id);
+            return Ok(PaymentOutcome::Processed);
+        }
+
+        self.record_status(&payment.correlation_id, PaymentStatus::Processing, None, None).await;
+
         payment.requested_at = Utc::now().to_rfc3339().clone();
 
-        match self.payment_processor.process(payment.clone()).await {
+        match self.payment_processor.clone().process(payment.clone()).await {
             Ok(payment_processor) => {
-                self.payment_store.create_payment(payment, payment_processor).await;
-                Ok(())
+                self.payment_store.create_payment(payment, payment_processor).await?;
+                Ok(PaymentOutcome::Processed)
             },
-            Err(e) => {
-                if publish_on_failure {
-                    let payload = serde_json::to_string(&payment).unwrap();
-                    self.producer.publish(payload).await.map_err(|e| {
-                        error!("failed to publish payment to queue");
-                        e.to_string()
-                    })
-                } else {
-                    // When coming from consumer, don't republish, just return the error
-                    Err(format!("Payment processing failed: {}", e))
-                }
-            }
+            // Queueing only helps when another attempt can succeed
+            Err(e) if publish_on_failure && e.is_retryable() => {
+                self.enqueue(payment.clone()).await?;
+                self.record_status(&payment.correlation_id, PaymentStatus::Retrying, None, Some(e.to_string())).await;
+                Ok(PaymentOutcome::Queued)
+            },
+            // A processor rejection is final whichever way the payment came in
+            Err(e) if publish_on_failure || matches!(e, CoreError::ProcessorPermanent(_)) => {
+                self.record_status(&payment.correlation_id, PaymentStatus::Failed, None, Some(e.to_string())).await;
+                Err(e)
+            },
+            // When coming from consumer, don't republish, just return the error
+            Err(e) => Err(e),
+        }
+    }
+
+    // Hands the payment to the worker pool; a payment the queue refused is failed for good
+    async fn enqueue(&self, mut payment: Payment) -> Result<(), CoreError> {
+        if payment.requested_at.is_empty() {
+            payment.requested_at = Utc::now().to_rfc3339();
+        }
+
+        let payload = serde_json::to_string(&QueuedPayment::from(&payment))
+            .map_err(|e| CoreError::Serialization(format!("Failed to serialize payment: {}", e)))?;
+        if let Err(e) = self.producer.publish(payload).await {
+            error!("failed to publish payment to queue");
+            self.record_status(&payment.correlation_id, PaymentStatus::Failed, None, Some(e.to_string())).await;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Best effort: a payment that cannot be moved keeps its previous status.
+    pub async fn record_status(&self, correlation_id: &str, status: PaymentStatus, retry_count: Option<u8>, detail: Option<String>) {
+        if let Err(e) = self.payment_store.transition(correlation_id, status, retry_count, detail).await {
+            error!("Failed to record payment status: {}", e);
         }
     }
 }
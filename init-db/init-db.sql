@@ -1,9 +1,48 @@
 CREATE UNLOGGED TABLE payments (
     correlation_id UUID PRIMARY KEY,
-    payment_processor VARCHAR(50) NOT NULL,
+    payment_processor VARCHAR(50),
     amount DECIMAL NOT NULL,
-    requested_at TIMESTAMP NOT NULL
+    requested_at TIMESTAMP NOT NULL,
+    status VARCHAR(20) NOT NULL,
+    retry_count SMALLINT NOT NULL DEFAULT 0,
+    received_at TIMESTAMPTZ,
+    processing_at TIMESTAMPTZ,
+    succeeded_at TIMESTAMPTZ,
+    retrying_at TIMESTAMPTZ,
+    dead_lettered_at TIMESTAMPTZ,
+    failed_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL
 );
 
 CREATE INDEX payments_requested_at ON payments (requested_at);
 CREATE INDEX payments_payment_processor ON payments (payment_processor);
+CREATE INDEX payments_status ON payments (status);
+
+CREATE UNLOGGED TABLE payment_status_history (
+    id BIGSERIAL PRIMARY KEY,
+    correlation_id UUID NOT NULL,
+    status VARCHAR(20) NOT NULL,
+    retry_count SMALLINT NOT NULL,
+    detail TEXT,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX payment_status_history_correlation_id ON payment_status_history (correlation_id);
+
+CREATE TABLE queue_messages (
+    id BIGSERIAL PRIMARY KEY,
+    topic VARCHAR(100) NOT NULL,
+    queue VARCHAR(20) NOT NULL,
+    payload TEXT NOT NULL,
+    available_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ,
+    lease_token UUID
+);
+
+CREATE INDEX queue_messages_ready ON queue_messages (topic, queue, available_at);
+
+CREATE UNLOGGED TABLE payment_requests (
+    correlation_id UUID PRIMARY KEY,
+    outcome VARCHAR(20) NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
@@ -1,15 +1,167 @@
 use serde::{Deserialize, Serialize};
-use config::{Case, Config};
+use config::{Case, Config, ConfigError};
+use shared::{ClientSettings, ProcessorConfig, parse_processors};
+use crate::routing::RoutingPolicyKind;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub server_url: String,
     pub server_port: u16,
-    pub payment_processor_default: String,
-    pub payment_processor_fallback: String,
+    // JSON list of upstreams, see `shared::ProcessorConfig`. Without it the default and fallback
+    // URLs below make up the list
+    pub payment_processors: Option<String>,
+    pub payment_processor_default: Option<String>,
+    pub payment_processor_fallback: Option<String>,
+    // Shares processor health with the other proxy and core replicas when set
+    pub redis_url: Option<String>,
+    #[serde(default = "default_replica_name")]
+    pub replica_name: String,
+    #[serde(default = "default_processor_health_check_interval_ms")]
+    pub processor_health_check_interval_ms: u64,
+    #[serde(flatten)]
+    pub client: ClientSettings,
+    #[serde(default = "default_routing_policy")]
+    pub routing_policy: RoutingPolicyKind,
+    #[serde(default = "default_routing_max_latency_ms")]
+    pub routing_max_latency_ms: u64,
+    #[serde(default = "default_processor_default_fee")]
+    pub processor_default_fee: f64,
+    #[serde(default = "default_processor_fallback_fee")]
+    pub processor_fallback_fee: f64,
+    #[serde(default = "default_circuit_breaker_window_size")]
+    pub circuit_breaker_window_size: usize,
+    #[serde(default = "default_circuit_breaker_failure_rate")]
+    pub circuit_breaker_failure_rate: f64,
+    #[serde(default = "default_circuit_breaker_min_requests")]
+    pub circuit_breaker_min_requests: usize,
+    #[serde(default = "default_circuit_breaker_cooldown_ms")]
+    pub circuit_breaker_cooldown_ms: u64,
+    #[serde(default = "default_circuit_breaker_half_open_probes")]
+    pub circuit_breaker_half_open_probes: u32,
+    // Without it only the first upstream by priority has a breaker, and the others take
+    // what it turns away
+    #[serde(default)]
+    pub circuit_breaker_per_upstream: bool,
+    // Replay a request the first processor failed on the other one, within the retry budget
+    #[serde(default)]
+    pub proxy_retry_on_fallback: bool,
+    #[serde(default = "default_proxy_retry_budget_ratio")]
+    pub proxy_retry_budget_ratio: f64,
+    #[serde(default = "default_proxy_retry_budget_max")]
+    pub proxy_retry_budget_max: f64,
+    // Longest a request may take, replays included. Keep it below the timeout of
+    // the caller, core's APP_PROCESSOR_REQUEST_TIMEOUT_MS, or the caller gives up and retries
+    // a payment the proxy may still be sending to another processor
+    #[serde(default = "default_proxy_request_deadline_ms")]
+    pub proxy_request_deadline_ms: u64,
+    // Send a payment that takes longer than most to the same processor again and keep whichever
+    // answer comes first. The processor takes a correlationId once, so at most one of the two is
+    // charged; the other processor is never hedged on, as it would take the payment as well
+    #[serde(default)]
+    pub proxy_hedge_enabled: bool,
+    #[serde(default = "default_proxy_hedge_percentile")]
+    pub proxy_hedge_percentile: f64,
+    #[serde(default = "default_proxy_hedge_min_samples")]
+    pub proxy_hedge_min_samples: usize,
+    #[serde(default = "default_proxy_hedge_min_delay_ms")]
+    pub proxy_hedge_min_delay_ms: u64,
+}
+
+// Each replica takes the health lease under its own name, so fall back to the container hostname
+fn default_replica_name() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| format!("proxy-{}", std::process::id()))
+}
+
+// The processors only answer one health check per caller every five seconds
+fn default_processor_health_check_interval_ms() -> u64 {
+    5_000
+}
+
+fn default_routing_policy() -> RoutingPolicyKind {
+    RoutingPolicyKind::NetAmount
+}
+
+// The priority policy avoids a processor reporting a slower minimum response time; zero
+// means no limit. The net amount policy weighs response times against the request timeout
+// instead
+fn default_routing_max_latency_ms() -> u64 {
+    1_000
+}
+
+fn default_processor_default_fee() -> f64 {
+    0.05
+}
+
+fn default_processor_fallback_fee() -> f64 {
+    0.15
+}
+
+// Number of latest requests the failure rate is taken over
+fn default_circuit_breaker_window_size() -> usize {
+    20
+}
+
+fn default_circuit_breaker_failure_rate() -> f64 {
+    0.5
+}
+
+// The breaker does not open before the window holds this many requests
+fn default_circuit_breaker_min_requests() -> usize {
+    10
+}
+
+fn default_circuit_breaker_cooldown_ms() -> u64 {
+    5_000
+}
+
+fn default_circuit_breaker_half_open_probes() -> u32 {
+    3
+}
+
+// Retries earned per request forwarded
+fn default_proxy_retry_budget_ratio() -> f64 {
+    0.2
+}
+
+// Most retries that can be saved up while everything works
+fn default_proxy_retry_budget_max() -> f64 {
+    10.0
+}
+
+// Leaves half a second of core's five second timeout for the answer to travel back
+fn default_proxy_request_deadline_ms() -> u64 {
+    4_500
+}
+
+// A payment is hedged once it takes longer than this share of recent ones did
+fn default_proxy_hedge_percentile() -> f64 {
+    0.95
+}
+
+// Latencies needed before the percentile is trusted; payments are not hedged until then
+fn default_proxy_hedge_min_samples() -> usize {
+    20
+}
+
+fn default_proxy_hedge_min_delay_ms() -> u64 {
+    20
 }
 
 impl Settings {
+    /// The configured upstreams, sorted by priority.
+    pub fn upstreams(&self) -> Result<Vec<ProcessorConfig>, ConfigError> {
+        parse_processors(self.payment_processors.as_deref(), || {
+            let (Some(default_url), Some(fallback_url)) = (self.payment_processor_default.clone(), self.payment_processor_fallback.clone()) else {
+                return Err(ConfigError::Message("Set APP_PAYMENT_PROCESSORS, or APP_PAYMENT_PROCESSOR_DEFAULT and APP_PAYMENT_PROCESSOR_FALLBACK".to_string()));
+            };
+
+            Ok(vec![
+                ProcessorConfig { fee: self.processor_default_fee, ..ProcessorConfig::new("default", default_url, 0) },
+                ProcessorConfig { fee: self.processor_fallback_fee, ..ProcessorConfig::new("fallback", fallback_url, 1) },
+            ])
+        })
+    }
+
     pub fn new() -> Self {
         let cfg = Config::builder()
             .add_source(config::Environment::with_prefix("APP")
@@ -20,4 +172,39 @@ impl Settings {
 
         cfg.try_deserialize().unwrap()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl Settings {
+    /// Settings for a local run, with the named settings in `overrides` (a JSON object)
+    /// on top.
+    pub fn for_tests(overrides: serde_json::Value) -> Self {
+        let mut settings = serde_json::json!({
+            "server_url": "127.0.0.1",
+            "server_port": 9999,
+        });
+        if let (Some(settings), serde_json::Value::Object(overrides)) = (settings.as_object_mut(), overrides) {
+            settings.extend(overrides);
+        }
+
+        serde_json::from_value(settings).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn gives_the_unlisted_upstreams_their_configured_fees() {
+        let settings = Settings::for_tests(json!({
+            "payment_processor_default": "http://default",
+            "payment_processor_fallback": "http://fallback",
+        }));
+
+        let fees = settings.upstreams().unwrap().into_iter().map(|upstream| (upstream.name, upstream.fee)).collect::<Vec<_>>();
+        assert_eq!(fees, [("default".to_string(), 0.05), ("fallback".to_string(), 0.15)]);
+        assert!(Settings::for_tests(json!({})).upstreams().is_err());
+    }
+}
@@ -1,29 +1,52 @@
 mod config;
-
+mod metrics;
+mod routing;
 
 use std::{
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use std::str::FromStr;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Error, http};
+use actix_web::dev::Service;
 use futures::StreamExt;
-use bytes::{BytesMut};
-use tokio::time::sleep;
+use futures::future::{Either, select};
+use serde_json::json;
+use bytes::{Bytes, BytesMut};
 use reqwest::header::HeaderName;
+use shared::HealthMonitor;
+use tokio::time::sleep;
 use tracing::{info};
 use tracing_subscriber::fmt;
 use crate::config::Settings;
+use crate::metrics::Metrics;
+use crate::routing::{Admission, CircuitBreaker, HedgePolicy, RetryBudget, RoutingPolicy, Upstream};
 
 #[derive(Clone)]
 struct AppState {
     client: reqwest::Client,
-    url_default: String,
-    url_fallback: String,
-    circuit_open: Arc<AtomicBool>,
+    // Sorted by priority
+    upstreams: Arc<Vec<Arc<Upstream>>>,
+    policy: RoutingPolicy,
+    retry_budget: Arc<RetryBudget>,
+    hedge: HedgePolicy,
+    // Time each processor has to answer
+    request_timeout: Duration,
+    // Time every request has to be answered in, however many processors it goes to
+    request_deadline: Duration,
+    metrics: Metrics,
+}
+
+// The processor a request names in `x-payment-processor`, like core looking up a payment
+// that processor answered for; no other processor would know about it
+fn pinned<'a>(req: &HttpRequest, upstreams: &'a [Arc<Upstream>]) -> Option<&'a Arc<Upstream>> {
+    let name = req.headers().get("x-payment-processor")?.to_str().ok()?;
+    upstreams.iter().find(|upstream| upstream.name == name)
+}
+
+// The first of `candidates` whose circuit breaker lets a request through, and its position
+fn admit<'a>(candidates: &[&'a Arc<Upstream>]) -> Option<(usize, Admission<'a>)> {
+    candidates.iter().enumerate().find_map(|(index, upstream)| upstream.admit().map(|admission| (index, admission)))
 }
 
 #[actix_web::main]
@@ -32,45 +55,63 @@ async fn main() -> std::io::Result<()> {
 
     let settings = Settings::new();
 
+    let upstreams = settings.upstreams()
+        .map_err(std::io::Error::other)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, config)| {
+            let breaker = (index == 0 || settings.circuit_breaker_per_upstream)
+                .then(|| CircuitBreaker::new(config.name.clone(), &settings));
+            info!("forwarding to {} at {}", config.name, config.url);
+            Arc::new(Upstream::new(config, breaker))
+        })
+        .collect::<Vec<_>>();
+
     let state = AppState {
-        client: reqwest::Client::new(),
-        url_default: settings.payment_processor_default.clone(),
-        url_fallback: settings.payment_processor_fallback.clone(),
-        circuit_open: Arc::new(AtomicBool::new(false)),
+        client: shared::build_client(&settings.client).map_err(std::io::Error::other)?,
+        upstreams: Arc::new(upstreams),
+        policy: RoutingPolicy::new(settings.routing_policy, settings.routing_max_latency_ms, settings.client.processor_request_timeout_ms),
+        retry_budget: Arc::new(RetryBudget::new(&settings)),
+        hedge: HedgePolicy::new(&settings),
+        request_timeout: settings.client.request_timeout(),
+        request_deadline: Duration::from_millis(settings.proxy_request_deadline_ms),
+        metrics: Metrics::new(),
     };
 
-    // spawn the health-checker
-    {
-        let hc = state.clone();
-        tokio::spawn(async move {
-            loop {
-                if hc.circuit_open.load(Ordering::SeqCst) {
-                    info!("checking default service health");
-                    let health_url = format!("{}/payments/service-health", hc.url_default);
-                    let client = reqwest::Client::new();
-
-                    if let Ok(resp) = client.get(&health_url).send().await {
-                        if resp.status().is_success() {
-                            if let Ok(json) = resp.json::<serde_json::Value>().await {
-                                if let Some(failing) = json.get("failing").and_then(|v| v.as_bool()) {
-                                    if !failing {
-                                        info!("default service is healthy again");
-                                        hc.circuit_open.store(false, Ordering::SeqCst);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    info!("routing payments with the {:?} policy", settings.routing_policy);
 
-                sleep(Duration::from_secs(5)).await;
-            }
-        });
-    }
+    // spawn the health-checker; only the replica holding the health lease polls the processors,
+    // and circuit breakers opened on one replica open on the others too
+    let monitor = HealthMonitor::new(
+        settings.redis_url.as_deref(),
+        settings.replica_name.clone(),
+        Duration::from_millis(settings.processor_health_check_interval_ms),
+        settings.client.request_timeout(),
+        state.upstreams.len(),
+    );
+    monitor.start(state.client.clone(), state.upstreams.clone());
 
     HttpServer::new(move || {
+        let metrics = state.metrics.clone();
+
         App::new()
+            .wrap_fn(move |req, srv| {
+                let metrics = metrics.clone();
+                let started = Instant::now();
+                let path = req.path().to_string();
+                let method = req.method().to_string();
+                let response = srv.call(req);
+
+                async move {
+                    let res = response.await?;
+                    metrics.observe_request(&path, &method, res.status().as_u16(), started.elapsed());
+                    Ok(res)
+                }
+            })
             .app_data(web::Data::new(state.clone()))
+            .route("/metrics", web::get().to(metrics_handler))
+            .route("/health/live", web::get().to(live_handler))
+            .route("/health/ready", web::get().to(ready_handler))
             .default_service(web::route().to(proxy_handler))
     })
         .bind((settings.server_url, settings.server_port))?
@@ -78,6 +119,40 @@ async fn main() -> std::io::Result<()> {
         .await
 }
 
+async fn metrics_handler(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let body = state.metrics.encode(&state.upstreams)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+// The process is up and serving requests; the processors are left to readiness
+async fn live_handler() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "up" }))
+}
+
+// Ready while at least one processor can take payments
+async fn ready_handler(state: web::Data<AppState>) -> HttpResponse {
+    let checks = state.upstreams.iter()
+        .map(|upstream| {
+            let check = json!({
+                "status": if upstream.is_available() { "up" } else { "down" },
+                "failing": upstream.is_failing(),
+                "circuit": upstream.circuit_state(),
+            });
+            (upstream.name.clone(), check)
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    if state.upstreams.iter().any(|upstream| upstream.is_available()) {
+        HttpResponse::Ok().json(json!({ "status": "up", "checks": checks }))
+    } else {
+        info!("no processor is available, reporting not ready");
+        HttpResponse::ServiceUnavailable().json(json!({ "status": "down", "checks": checks }))
+    }
+}
+
 async fn proxy_handler(
     req: HttpRequest,
     mut body: web::Payload,
@@ -85,41 +160,175 @@ async fn proxy_handler(
 ) -> Result<HttpResponse, Error> {
     info!("proxying request to {}", req.uri());
 
-    // 1) Choose default vs fallback
-    let (base, processor_value) = if state.circuit_open.load(Ordering::SeqCst) {
-        (&state.url_fallback, "fallback")
-    } else {
-        (&state.url_default, "default")
+    // 1) Collect full request body, so it can be sent again to the other processor. It is
+    //    read before a processor is chosen, so a client that goes away takes nothing with it
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(Error::from)?;
+        buf.extend_from_slice(&chunk);
+    }
+    let body = buf.freeze();
+
+    info!("forwarding request with body {}", String::from_utf8_lossy(&body));
+
+    let deadline = Instant::now() + state.request_deadline;
+
+    // 2) Choose the processor; the ones ranked after it are kept for replays
+    let ranked = match pinned(&req, &state.upstreams) {
+        Some(upstream) => vec![upstream],
+        None => state.policy.rank(&state.upstreams),
+    };
+    let Some((chosen, mut admission)) = admit(&ranked) else {
+        info!("circuit breakers are open for every processor, rejecting request");
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    };
+    let alternates = &ranked[chosen + 1..];
+
+    state.retry_budget.deposit();
+
+    // 3) Send it, replaying it once on the other processor when the first one fails and there
+    //    is still time before the deadline
+    let mut replayed = false;
+    let (upstream, resp) = loop {
+        let upstream = admission.upstream();
+        let result = send(&state, &req, upstream, body.clone(), deadline).await;
+
+        if is_success(&result) {
+            admission.record_success();
+        } else {
+            admission.record_failure();
+        }
+
+        // A request that timed out may still have been taken, so it is never replayed
+        let replayable = match &result {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(e) => !e.is_timeout(),
+        };
+
+        if replayable && !replayed
+            && let Some((_, other)) = admit(alternates)
+            && has_time_for(other.upstream(), deadline)
+            && state.retry_budget.try_withdraw()
+        {
+            info!("{} processor failed, replaying request on {}", upstream.name, other.upstream().name);
+            admission = other;
+            replayed = true;
+            state.metrics.inc_replays();
+            continue;
+        }
+
+        let resp = result.map_err(|e| {
+            tracing::error!("Failed to send request to payment processor: {}", e);
+            upstream_error(e)
+        })?;
+        break (upstream, resp);
+    };
+
+    info!("received response with status {}", resp.status());
+
+    // 4) Build Actix response from reqwest::Response
+    let status = resp.status();
+    let mut client_resp = HttpResponse::build(http::StatusCode::from_u16(status.as_u16()).unwrap());
+
+    for (name, value) in resp.headers().iter() {
+        let header_name = http::header::HeaderName::from_str(name.as_str()).unwrap();
+        let header_value = http::header::HeaderValue::from_str(value.to_str().unwrap())?;
+        client_resp.insert_header((header_name, header_value));
+    }
+
+    // Add x-payment-processor header to indicate which processor finally answered
+    client_resp.insert_header(("x-payment-processor", upstream.name.as_str()));
+
+    let bytes = resp
+        .bytes()
+        .await
+        .map_err(upstream_error)?;
+
+    Ok(client_resp.body(bytes))
+}
+
+// Whether a request sent to `upstream` now could be answered before the deadline, going by the
+// fastest the processor says it answers
+fn has_time_for(upstream: &Upstream, deadline: Instant) -> bool {
+    deadline.saturating_duration_since(Instant::now()) > Duration::from_millis(upstream.min_response_time_ms())
+}
+
+// Only 5xx answers count against the processor, 4xx are about the request
+fn is_success(result: &Result<reqwest::Response, reqwest::Error>) -> bool {
+    matches!(result, Ok(resp) if !resp.status().is_server_error())
+}
+
+// Sends the request to `upstream`, and sends a payment to it a second time when it takes
+// longer than the hedge delay. Whichever of the two settles the payment first is answered
+// with and the other is cancelled, so the processor's outcome is recorded once
+async fn send(
+    state: &AppState,
+    req: &HttpRequest,
+    upstream: &Upstream,
+    body: Bytes,
+    deadline: Instant,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let primary = Box::pin(forward(state, req, upstream, body.clone(), deadline));
+
+    let Some(delay) = state.hedge.delay(upstream).filter(|_| is_payment(req)) else {
+        return primary.await;
+    };
+
+    let primary = match select(primary, Box::pin(sleep(delay))).await {
+        Either::Left((result, _)) => return result,
+        Either::Right((_, primary)) => primary,
+    };
+
+    if !has_time_for(upstream, deadline) {
+        return primary.await;
+    }
+
+    info!("{} processor did not answer within {:?}, hedging on it", upstream.name, delay);
+    state.metrics.inc_hedges();
+    let hedge = Box::pin(forward(state, req, upstream, body, deadline));
+
+    let (first, other) = match select(primary, hedge).await {
+        Either::Left(answered) | Either::Right(answered) => answered,
     };
 
-    // 2) Rebuild target URL (path + query)
+    if HedgePolicy::settles(&first) {
+        return first;
+    }
+    HedgePolicy::preferred(first, other.await)
+}
+
+// Payments are the only requests hedged, as the processor takes each correlationId once
+fn is_payment(req: &HttpRequest) -> bool {
+    req.method() == http::Method::POST && req.path() == "/payments"
+}
+
+// Gives up at the deadline, sooner than the client's own request timeout
+async fn forward(
+    state: &AppState,
+    req: &HttpRequest,
+    upstream: &Upstream,
+    body: Bytes,
+    deadline: Instant,
+) -> Result<reqwest::Response, reqwest::Error> {
+    // Rebuild target URL (path + query)
     let path_q = req
         .uri()
         .path_and_query()
         .map(|pq| pq.as_str())
         .unwrap_or("/");
-    let url = format!("{}{}", base, path_q);
+    let url = format!("{}{}", upstream.base_url, path_q);
 
     info!("forwarding request to {}", url);
 
-    // 3) Collect full request body
-    let mut buf = BytesMut::new();
-    while let Some(chunk) = body.next().await {
-        let chunk = chunk.map_err(Error::from)?;
-        buf.extend_from_slice(&chunk);
-    }
-
-    info!("forwarding request with body {}", String::from_utf8_lossy(&buf));
-
-    // 4) Build reqwest request with same method, headers, and body
+    // Build reqwest request with same method, headers, and body
     let method = reqwest::Method::from_str(req.method().as_str()).unwrap();
     let is_post = method == reqwest::Method::POST;
 
     info!("forwarding request with method {}", method);
 
-    let mut builder = state
-        .client
-        .request(method, &url);
+    let mut builder = state.client
+        .request(method, &url)
+        .timeout(deadline.saturating_duration_since(Instant::now()).min(state.request_timeout));
 
     // Ensure Content-Type is set to application/json for POST requests
     if is_post {
@@ -137,41 +346,26 @@ async fn proxy_handler(
         }
     }
 
-    let resp = builder
-        .body(buf.freeze())
-        .send()
-        .await
-        .map_err(|e|{
-            tracing::error!("Failed to send request to payment processor: {}", e);
-            actix_web::error::ErrorBadGateway(e)
-        })?;
-
-    info!("received response with status {}", resp.status());
-
-    // 5) Build Actix response from reqwest::Response
-    let status = resp.status();
-    let mut client_resp = HttpResponse::build(http::StatusCode::from_u16(status.as_u16()).unwrap());
-
-    // Add x-payment-processor header to indicate which processor was used
-    client_resp.insert_header(("x-payment-processor", processor_value));
+    let started = Instant::now();
+    let result = builder.body(body).send().await;
+    state.metrics.observe_upstream(&upstream.name, &result, started.elapsed());
 
-    for (name, value) in resp.headers().iter() {
-        let header_name = http::header::HeaderName::from_str(name.as_str()).unwrap();
-        let header_value = http::header::HeaderValue::from_str(value.to_str().unwrap())?;
-        client_resp.insert_header((header_name, header_value));
+    if let Ok(resp) = &result
+        && resp.status().is_success()
+    {
+        upstream.record_latency(started.elapsed());
     }
 
-    let bytes = resp
-        .bytes()
-        .await
-        .map_err(actix_web::error::ErrorBadGateway)?;
+    result
+}
 
-    // Circuit breaker: trip only on 5xx errors
-    if status.is_server_error() {
-        state.circuit_open.store(true, Ordering::SeqCst);
+// A processor that is too slow is reported apart from one that cannot be reached
+fn upstream_error(e: reqwest::Error) -> Error {
+    if e.is_timeout() {
+        actix_web::error::ErrorGatewayTimeout(e)
+    } else {
+        actix_web::error::ErrorBadGateway(e)
     }
-
-    Ok(client_resp.body(bytes))
 }
 
 fn init_tracing() {
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::Error;
+use actix_web::error::ErrorInternalServerError;
+use prometheus::{Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use crate::routing::Upstream;
+
+// Every state a circuit breaker can be in, so the ones it left drop back to 0
+const CIRCUIT_STATES: [&str; 3] = ["closed", "open", "half-open"];
+
+/// Groups request paths so every correlation id does not become its own series.
+fn route_label(path: &str) -> &'static str {
+    match path {
+        "/payments" => "/payments",
+        "/payments/service-health" => "/payments/service-health",
+        "/metrics" => "/metrics",
+        "/health/live" => "/health/live",
+        "/health/ready" => "/health/ready",
+        _ if path.starts_with("/payments/") => "/payments/{id}",
+        _ => "other",
+    }
+}
+
+/// Everything the proxy reports on `/metrics`. Clones share the same collectors.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    upstream_requests: IntCounterVec,
+    upstream_request_duration: HistogramVec,
+    circuit_state: IntGaugeVec,
+    upstream_failing: IntGaugeVec,
+    upstream_error_rate: GaugeVec,
+    replays: IntCounter,
+    hedges: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("proxy".to_string()), None).unwrap();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served, by route, method and status"),
+            &["route", "method", "status"],
+        ).unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time spent serving HTTP requests, by route and method"),
+            &["route", "method"],
+        ).unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new("upstream_requests_total", "Requests sent to each processor, by outcome"),
+            &["upstream", "outcome"],
+        ).unwrap();
+        let upstream_request_duration = HistogramVec::new(
+            HistogramOpts::new("upstream_request_duration_seconds", "Time each processor took to answer"),
+            &["upstream"],
+        ).unwrap();
+        let circuit_state = IntGaugeVec::new(
+            Opts::new("circuit_state", "1 for the state each processor's circuit breaker is in"),
+            &["upstream", "state"],
+        ).unwrap();
+        let upstream_failing = IntGaugeVec::new(
+            Opts::new("upstream_failing", "1 while a processor reports itself as failing"),
+            &["upstream"],
+        ).unwrap();
+        let upstream_error_rate = GaugeVec::new(
+            Opts::new("upstream_error_rate", "Moving average of failed requests to each processor"),
+            &["upstream"],
+        ).unwrap();
+        let replays = IntCounter::new("replays_total", "Failed requests replayed on another processor").unwrap();
+        let hedges = IntCounter::new("hedges_total", "Slow payments sent to their processor a second time").unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_request_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_requests.clone())).unwrap();
+        registry.register(Box::new(upstream_request_duration.clone())).unwrap();
+        registry.register(Box::new(circuit_state.clone())).unwrap();
+        registry.register(Box::new(upstream_failing.clone())).unwrap();
+        registry.register(Box::new(upstream_error_rate.clone())).unwrap();
+        registry.register(Box::new(replays.clone())).unwrap();
+        registry.register(Box::new(hedges.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            upstream_requests,
+            upstream_request_duration,
+            circuit_state,
+            upstream_failing,
+            upstream_error_rate,
+            replays,
+            hedges,
+        }
+    }
+
+    pub fn observe_request(&self, path: &str, method: &str, status: u16, elapsed: Duration) {
+        let route = route_label(path);
+        self.http_requests.with_label_values(&[route, method, &status.to_string()]).inc();
+        self.http_request_duration.with_label_values(&[route, method]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_upstream(&self, upstream: &str, result: &Result<reqwest::Response, reqwest::Error>, elapsed: Duration) {
+        let outcome = match result {
+            Ok(resp) if resp.status().is_server_error() => "5xx",
+            Ok(resp) if resp.status().is_client_error() => "4xx",
+            Ok(_) => "success",
+            Err(e) if e.is_timeout() => "timeout",
+            Err(_) => "error",
+        };
+        self.upstream_requests.with_label_values(&[upstream, outcome]).inc();
+        self.upstream_request_duration.with_label_values(&[upstream]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn inc_replays(&self) {
+        self.replays.inc();
+    }
+
+    pub fn inc_hedges(&self) {
+        self.hedges.inc();
+    }
+
+
+    /// Renders every collector in the Prometheus text format, after copying in what each
+    /// processor looks like right now.
+    pub fn encode(&self, upstreams: &[Arc<Upstream>]) -> Result<String, Error> {
+        for upstream in upstreams {
+            if let Some(current) = upstream.circuit_state() {
+                for state in CIRCUIT_STATES {
+                    self.circuit_state.with_label_values(&[&upstream.name, state]).set((state == current) as i64);
+                }
+            }
+            self.upstream_failing.with_label_values(&[&upstream.name]).set(upstream.is_failing() as i64);
+            self.upstream_error_rate.with_label_values(&[&upstream.name]).set(upstream.error_rate());
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(ErrorInternalServerError)?;
+
+        String::from_utf8(buffer).map_err(ErrorInternalServerError)
+    }
+}
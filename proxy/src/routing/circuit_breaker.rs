@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+use crate::config::Settings;
+
+#[derive(Debug)]
+enum BreakerState {
+    Closed,
+    Open { since: Instant },
+    // Counts the probes let through and how many of them came back fine
+    HalfOpen { admitted: u32, succeeded: u32 },
+}
+
+impl BreakerState {
+    fn name(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half-open",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    // Outcomes of the latest requests while closed, true for a failure
+    window: VecDeque<bool>,
+    // Set when failures opened the breaker, until the other replicas have been told
+    opened: bool,
+}
+
+/// Stops sending requests to a processor once too many of the latest ones failed.
+/// After the cooldown a few probes go through; the breaker closes if they all succeed
+/// and opens again on the first failure.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    window_size: usize,
+    failure_rate: f64,
+    min_requests: usize,
+    cooldown: Duration,
+    half_open_probes: u32,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: String, settings: &Settings) -> Self {
+        Self {
+            name,
+            window_size: settings.circuit_breaker_window_size,
+            failure_rate: settings.circuit_breaker_failure_rate,
+            min_requests: settings.circuit_breaker_min_requests,
+            cooldown: Duration::from_millis(settings.circuit_breaker_cooldown_ms),
+            half_open_probes: settings.circuit_breaker_half_open_probes,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                window: VecDeque::with_capacity(settings.circuit_breaker_window_size),
+                opened: false,
+            }),
+        }
+    }
+
+    /// Name of the current state, for the metrics.
+    pub fn state(&self) -> &'static str {
+        self.inner.lock().unwrap().state.name()
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Whether failures opened the breaker since the last call.
+    pub fn take_opened(&self) -> bool {
+        std::mem::take(&mut self.inner.lock().unwrap().opened)
+    }
+
+    /// Opens a closed breaker for a full cooldown, as another replica's did. It does not
+    /// count as opened here, so it is not passed on again.
+    pub fn open(&self) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        if let BreakerState::Closed = inner.state {
+            inner.window.clear();
+            self.transition(inner, BreakerState::Open { since: Instant::now() });
+        }
+    }
+
+    /// Whether a request may go to the processor now. Moves an open breaker whose
+    /// cooldown is over to half-open, and counts the request as a probe when half-open.
+    pub fn try_acquire(&self) -> bool {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open { since } => {
+                if since.elapsed() < self.cooldown {
+                    return false;
+                }
+                self.transition(inner, BreakerState::HalfOpen { admitted: 1, succeeded: 0 });
+                true
+            },
+            BreakerState::HalfOpen { ref mut admitted, .. } => {
+                if *admitted >= self.half_open_probes {
+                    return false;
+                }
+                *admitted += 1;
+                true
+            },
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        match inner.state {
+            BreakerState::Closed => self.push_outcome(inner, false),
+            BreakerState::Open { .. } => {},
+            BreakerState::HalfOpen { ref mut succeeded, .. } => {
+                *succeeded += 1;
+                if *succeeded >= self.half_open_probes {
+                    inner.window.clear();
+                    self.transition(inner, BreakerState::Closed);
+                }
+            },
+        }
+    }
+
+    /// Gives back a half-open probe that was let through but never sent or never answered,
+    /// so the breaker can admit another one in its place.
+    pub fn release(&self) {
+        let mut guard = self.inner.lock().unwrap();
+
+        if let BreakerState::HalfOpen { ref mut admitted, .. } = guard.state {
+            *admitted = admitted.saturating_sub(1);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        match inner.state {
+            BreakerState::Closed => {
+                self.push_outcome(inner, true);
+
+                let failures = inner.window.iter().filter(|failed| **failed).count();
+                let requests = inner.window.len();
+                if requests >= self.min_requests && failures as f64 / requests as f64 >= self.failure_rate {
+                    inner.opened = true;
+                    self.transition(inner, BreakerState::Open { since: Instant::now() });
+                }
+            },
+            BreakerState::Open { .. } => {},
+            BreakerState::HalfOpen { .. } => {
+                inner.opened = true;
+                self.transition(inner, BreakerState::Open { since: Instant::now() });
+            },
+        }
+    }
+
+    fn push_outcome(&self, inner: &mut BreakerInner, failed: bool) {
+        if inner.window.len() >= self.window_size {
+            inner.window.pop_front();
+        }
+        inner.window.push_back(failed);
+    }
+
+    fn transition(&self, inner: &mut BreakerInner, state: BreakerState) {
+        info!("{} circuit breaker: {} -> {}", self.name, inner.state.name(), state.name());
+        inner.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(20);
+
+    // Opens once half of the latest 4 requests failed, and closes after 2 good probes
+    fn breaker() -> CircuitBreaker {
+        let settings = Settings::for_tests(json!({
+            "circuit_breaker_window_size": 4,
+            "circuit_breaker_failure_rate": 0.5,
+            "circuit_breaker_min_requests": 4,
+            "circuit_breaker_cooldown_ms": COOLDOWN.as_millis() as u64,
+            "circuit_breaker_half_open_probes": 2,
+        }));
+
+        CircuitBreaker::new("default".to_string(), &settings)
+    }
+
+    fn open(breaker: &CircuitBreaker) {
+        for _ in 0..4 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), "open");
+    }
+
+    fn half_open(breaker: &CircuitBreaker) {
+        open(breaker);
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), "half-open");
+    }
+
+    #[test]
+    fn stays_closed_until_the_window_holds_enough_requests() {
+        let breaker = breaker();
+
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), "closed");
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), "open");
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn opens_on_the_failure_rate_of_the_latest_requests() {
+        let breaker = breaker();
+
+        breaker.record_failure();
+        for _ in 0..4 {
+            breaker.record_success();
+        }
+        // The early failure has left the window
+        breaker.record_failure();
+        assert_eq!(breaker.state(), "closed");
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), "open");
+    }
+
+    #[test]
+    fn half_opens_once_the_cooldown_is_over() {
+        let breaker = breaker();
+        open(&breaker);
+
+        assert!(!breaker.try_acquire());
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), "half-open");
+    }
+
+    #[test]
+    fn admits_a_limited_number_of_probes() {
+        let breaker = breaker();
+        half_open(&breaker);
+
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+
+        // A probe that was never answered makes room for another one
+        breaker.release();
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn closes_once_every_probe_succeeded() {
+        let breaker = breaker();
+        half_open(&breaker);
+        assert!(breaker.try_acquire());
+
+        breaker.record_success();
+        assert_eq!(breaker.state(), "half-open");
+        breaker.record_success();
+        assert_eq!(breaker.state(), "closed");
+
+        // The failures from before start over
+        breaker.record_failure();
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn reopens_on_a_failed_probe() {
+        let breaker = breaker();
+        half_open(&breaker);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), "open");
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn reports_opening_on_its_own_failures_once() {
+        let breaker = breaker();
+        open(&breaker);
+
+        assert!(breaker.take_opened());
+        assert!(!breaker.take_opened());
+    }
+
+    #[test]
+    fn opens_for_another_replica_without_passing_it_on() {
+        let breaker = breaker();
+
+        breaker.open();
+        assert_eq!(breaker.state(), "open");
+        assert!(!breaker.try_acquire());
+        assert!(!breaker.take_opened());
+
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.try_acquire());
+        // Only a closed breaker is opened this way
+        breaker.open();
+        assert_eq!(breaker.state(), "half-open");
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+use reqwest::StatusCode;
+use crate::config::Settings;
+use crate::routing::upstream::Upstream;
+
+/// Decides how long a payment waits on its processor before it is sent to that processor a
+/// second time. Only the same processor is hedged on: it takes a correlationId once, so the
+/// two requests cannot both be charged, while another processor would take it again.
+#[derive(Clone, Debug)]
+pub struct HedgePolicy {
+    enabled: bool,
+    percentile: f64,
+    min_samples: usize,
+    min_delay: Duration,
+}
+
+impl HedgePolicy {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            enabled: settings.proxy_hedge_enabled,
+            percentile: settings.proxy_hedge_percentile,
+            min_samples: settings.proxy_hedge_min_samples,
+            min_delay: Duration::from_millis(settings.proxy_hedge_min_delay_ms),
+        }
+    }
+
+    /// How long `upstream` gets to answer before the payment is hedged, or `None` when it
+    /// is not hedged at all, either because hedging is off or because too few of its
+    /// latencies are known yet.
+    pub fn delay(&self, upstream: &Upstream) -> Option<Duration> {
+        if !self.enabled {
+            return None;
+        }
+
+        upstream.latency_percentile(self.percentile, self.min_samples)
+            .map(|delay| delay.max(self.min_delay))
+    }
+
+    /// Whether an answer settles a hedged payment on its own, so the other request can be
+    /// cancelled. An answer saying the correlationId was taken only means the other request
+    /// got there, and a failure leaves it to the other request.
+    pub fn settles(result: &Result<reqwest::Response, reqwest::Error>) -> bool {
+        matches!(result, Ok(resp) if resp.status().is_success())
+    }
+
+    /// The answer to give for a hedged payment once neither request settled it. One saying the
+    /// correlationId was taken tells the caller the payment went through, which it can look up,
+    /// so it wins over a failure; otherwise the first answer stands.
+    pub fn preferred(
+        first: Result<reqwest::Response, reqwest::Error>,
+        second: Result<reqwest::Response, reqwest::Error>,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        if !is_duplicate(&first) && (Self::settles(&second) || is_duplicate(&second)) {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+// Statuses the processor uses when the correlation id was already taken
+fn is_duplicate(result: &Result<reqwest::Response, reqwest::Error>) -> bool {
+    matches!(result, Ok(resp) if resp.status() == StatusCode::CONFLICT || resp.status() == StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use shared::ProcessorConfig;
+    use super::*;
+
+    fn answer(status: u16) -> Result<reqwest::Response, reqwest::Error> {
+        Ok(reqwest::Response::from(http::Response::builder().status(status).body("").unwrap()))
+    }
+
+    fn status(result: Result<reqwest::Response, reqwest::Error>) -> u16 {
+        result.unwrap().status().as_u16()
+    }
+
+    fn upstream_answering_in(latencies_ms: impl IntoIterator<Item = u64>) -> Upstream {
+        let upstream = Upstream::new(ProcessorConfig::new("default", "http://default".to_string(), 0), None);
+        latencies_ms.into_iter().for_each(|ms| upstream.record_latency(Duration::from_millis(ms)));
+        upstream
+    }
+
+    #[test]
+    fn is_off_unless_enabled() {
+        let upstream = upstream_answering_in([100; 50]);
+
+        assert_eq!(HedgePolicy::new(&Settings::for_tests(json!({}))).delay(&upstream), None);
+
+        let policy = HedgePolicy::new(&Settings::for_tests(json!({ "proxy_hedge_enabled": true })));
+        assert_eq!(policy.delay(&upstream), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn waits_for_enough_latencies_and_at_least_the_minimum_delay() {
+        let policy = HedgePolicy::new(&Settings::for_tests(json!({
+            "proxy_hedge_enabled": true,
+            "proxy_hedge_min_samples": 3,
+            "proxy_hedge_min_delay_ms": 20,
+        })));
+
+        assert_eq!(policy.delay(&upstream_answering_in([5, 5])), None);
+        assert_eq!(policy.delay(&upstream_answering_in([5, 5, 5])), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn only_a_success_settles_a_hedged_payment() {
+        assert!(HedgePolicy::settles(&answer(200)));
+        assert!(!HedgePolicy::settles(&answer(422)));
+        assert!(!HedgePolicy::settles(&answer(500)));
+    }
+
+    #[test]
+    fn prefers_an_answer_saying_the_payment_went_through() {
+        assert_eq!(status(HedgePolicy::preferred(answer(500), answer(200))), 200);
+        assert_eq!(status(HedgePolicy::preferred(answer(500), answer(409))), 409);
+        assert_eq!(status(HedgePolicy::preferred(answer(422), answer(500))), 422);
+        assert_eq!(status(HedgePolicy::preferred(answer(502), answer(500))), 502);
+    }
+}
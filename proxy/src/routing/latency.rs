@@ -0,0 +1,69 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Response times of the latest successful requests to one processor.
+#[derive(Debug)]
+pub struct LatencyWindow {
+    capacity: usize,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+
+        if samples.len() >= self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// The latency `percentile` (between 0 and 1) of the window, once it holds `min_samples`.
+    pub fn percentile(&self, percentile: f64, min_samples: usize) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = {
+            let samples = self.samples.lock().unwrap();
+            if samples.is_empty() || samples.len() < min_samples {
+                return None;
+            }
+            samples.iter().copied().collect()
+        };
+        sorted.sort_unstable();
+
+        let index = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+        sorted.get(index.min(sorted.len() - 1)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(latencies_ms: impl IntoIterator<Item = u64>) -> LatencyWindow {
+        let window = LatencyWindow::new(10);
+        latencies_ms.into_iter().for_each(|ms| window.record(Duration::from_millis(ms)));
+        window
+    }
+
+    #[test]
+    fn needs_enough_samples_for_a_percentile() {
+        assert_eq!(window([]).percentile(0.5, 0), None);
+        assert_eq!(window([10, 20]).percentile(0.5, 3), None);
+        assert_eq!(window([10, 20, 30]).percentile(0.5, 3), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn keeps_only_the_latest_samples() {
+        let window = window((1..=20).map(|ms| ms * 10));
+
+        assert_eq!(window.percentile(0.0, 1), Some(Duration::from_millis(110)));
+        assert_eq!(window.percentile(1.0, 1), Some(Duration::from_millis(200)));
+    }
+}
@@ -0,0 +1,12 @@
+mod circuit_breaker;
+mod hedge;
+mod latency;
+mod policy;
+mod retry_budget;
+mod upstream;
+
+pub use circuit_breaker::{CircuitBreaker};
+pub use hedge::{HedgePolicy};
+pub use policy::{RoutingPolicy, RoutingPolicyKind};
+pub use retry_budget::{RetryBudget};
+pub use upstream::{Admission, Upstream};
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+use crate::routing::upstream::Upstream;
+
+/// How the proxy chooses the processor for each request.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingPolicyKind {
+    // Whichever processor is expected to settle the most money net of fees, errors and timeouts
+    NetAmount,
+    // The first by priority unless it is failing or slower than the latency limit
+    #[serde(alias = "prefer_default")]
+    Priority,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoutingPolicy {
+    kind: RoutingPolicyKind,
+    max_latency_ms: u64,
+    // How long a request may take before the proxy gives up on it
+    request_timeout_ms: u64,
+    // Advances on every request to spread traffic by weight among equally ranked upstreams
+    turn: Arc<AtomicU64>,
+}
+
+impl RoutingPolicy {
+    pub fn new(kind: RoutingPolicyKind, max_latency_ms: u64, request_timeout_ms: u64) -> Self {
+        Self {
+            kind,
+            max_latency_ms,
+            request_timeout_ms,
+            turn: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Every upstream, best first. `upstreams` must be sorted by priority, which breaks the
+    /// ties the policy leaves.
+    pub fn rank<'a>(&self, upstreams: &'a [Arc<Upstream>]) -> Vec<&'a Arc<Upstream>> {
+        let mut ranked: Vec<&Arc<Upstream>> = upstreams.iter().collect();
+
+        match self.kind {
+            RoutingPolicyKind::NetAmount => ranked.sort_by(|a, b| self.expected_net(b).total_cmp(&self.expected_net(a))),
+            RoutingPolicyKind::Priority => ranked.sort_by_key(|upstream| !self.is_usable(upstream)),
+        }
+
+        self.rotate_leaders(&mut ranked);
+
+        ranked
+    }
+
+    // Puts one of the upstreams tied for first place in front, each getting its turn as
+    // often as its weight says
+    fn rotate_leaders(&self, ranked: &mut [&Arc<Upstream>]) {
+        let Some(first) = ranked.first().copied() else {
+            return;
+        };
+        let leaders = ranked.iter().take_while(|upstream| self.ties(first, upstream)).count();
+        let total_weight: u64 = ranked[..leaders].iter().map(|upstream| upstream.weight as u64).sum();
+        if leaders < 2 || total_weight == 0 {
+            return;
+        }
+
+        let mut turn = self.turn.fetch_add(1, Ordering::Relaxed) % total_weight;
+        let chosen = ranked[..leaders].iter()
+            .position(|upstream| {
+                if turn < upstream.weight as u64 {
+                    return true;
+                }
+                turn -= upstream.weight as u64;
+                false
+            })
+            .unwrap_or(0);
+
+        ranked[..leaders].rotate_left(chosen);
+    }
+
+    fn ties(&self, a: &Upstream, b: &Upstream) -> bool {
+        a.priority == b.priority && match self.kind {
+            RoutingPolicyKind::NetAmount => self.expected_net(a) == self.expected_net(b),
+            RoutingPolicyKind::Priority => self.is_usable(a) == self.is_usable(b),
+        }
+    }
+
+    fn is_usable(&self, upstream: &Upstream) -> bool {
+        !upstream.is_failing() && !self.is_too_slow(upstream)
+    }
+
+    // A processor slower than the limit would mostly time out, so it is treated as failing
+    fn is_too_slow(&self, upstream: &Upstream) -> bool {
+        self.max_latency_ms > 0 && upstream.min_response_time_ms() > self.max_latency_ms
+    }
+
+    // Chance a request times out, taken as how much of the timeout the processor's reported
+    // minimum response time already uses up
+    fn timeout_probability(&self, upstream: &Upstream) -> f64 {
+        if self.request_timeout_ms == 0 {
+            return 0.0;
+        }
+
+        (upstream.min_response_time_ms() as f64 / self.request_timeout_ms as f64).clamp(0.0, 1.0)
+    }
+
+    // Share of each payment we expect to keep: what is left after the fee, times the chance
+    // the processor settles it at all and in time
+    fn expected_net(&self, upstream: &Upstream) -> f64 {
+        if upstream.is_failing() {
+            return 0.0;
+        }
+
+        (1.0 - upstream.fee) * (1.0 - upstream.error_rate()) * (1.0 - self.timeout_probability(upstream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{HealthReport, MonitoredProcessor, ProcessorConfig};
+
+    fn upstream(name: &str, priority: u32, fee: f64, min_response_time: u64) -> Arc<Upstream> {
+        let upstream = Upstream::new(ProcessorConfig { fee, ..ProcessorConfig::new(name, format!("http://{}", name), priority) }, None);
+        upstream.apply_health(&HealthReport { failing: false, min_response_time, checked_at: 0 });
+        Arc::new(upstream)
+    }
+
+    fn names(ranked: Vec<&Arc<Upstream>>) -> Vec<&str> {
+        ranked.into_iter().map(|upstream| upstream.name.as_str()).collect()
+    }
+
+    #[test]
+    fn net_amount_prefers_the_cheaper_processor() {
+        let policy = RoutingPolicy::new(RoutingPolicyKind::NetAmount, 1_000, 5_000);
+        let upstreams = vec![upstream("default", 0, 0.15, 10), upstream("fallback", 1, 0.05, 10)];
+
+        assert_eq!(names(policy.rank(&upstreams)), ["fallback", "default"]);
+    }
+
+    #[test]
+    fn net_amount_weighs_slow_processors_down() {
+        let policy = RoutingPolicy::new(RoutingPolicyKind::NetAmount, 1_000, 5_000);
+
+        // A tenth of the timeout used up costs less than the fee difference
+        let upstreams = vec![upstream("default", 0, 0.05, 500), upstream("fallback", 1, 0.15, 10)];
+        assert_eq!(names(policy.rank(&upstreams)), ["default", "fallback"]);
+
+        // Half of it costs more, although neither is cut off the way the priority policy does
+        let upstreams = vec![upstream("default", 0, 0.05, 2_500), upstream("fallback", 1, 0.15, 10)];
+        assert_eq!(names(policy.rank(&upstreams)), ["fallback", "default"]);
+    }
+
+    #[test]
+    fn priority_skips_processors_over_the_latency_limit() {
+        let policy = RoutingPolicy::new(RoutingPolicyKind::Priority, 1_000, 5_000);
+
+        let upstreams = vec![upstream("default", 0, 0.05, 10), upstream("fallback", 1, 0.15, 10)];
+        assert_eq!(names(policy.rank(&upstreams)), ["default", "fallback"]);
+
+        let upstreams = vec![upstream("default", 0, 0.05, 1_500), upstream("fallback", 1, 0.15, 10)];
+        assert_eq!(names(policy.rank(&upstreams)), ["fallback", "default"]);
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::config::Settings;
+
+// Tokens are kept in thousandths so fractional deposits fit in an integer
+const SCALE: f64 = 1000.0;
+
+/// Caps replays on the other processor to a share of the traffic, so an outage of one
+/// processor does not double the load on the other. Every request deposits `ratio` of a
+/// retry, up to `max` retries saved, and each replay withdraws a whole one.
+#[derive(Debug)]
+pub struct RetryBudget {
+    enabled: bool,
+    deposit: u64,
+    max: u64,
+    balance: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(settings: &Settings) -> Self {
+        let max = (settings.proxy_retry_budget_max * SCALE) as u64;
+
+        Self {
+            enabled: settings.proxy_retry_on_fallback,
+            deposit: (settings.proxy_retry_budget_ratio * SCALE) as u64,
+            max,
+            // Start full so the first failures after a restart can be replayed
+            balance: AtomicU64::new(max),
+        }
+    }
+
+    pub fn deposit(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        let _ = self.balance.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |balance| {
+            Some((balance + self.deposit).min(self.max))
+        });
+    }
+
+    /// Takes one retry out of the budget, or says there is none to spend.
+    pub fn try_withdraw(&self) -> bool {
+        self.enabled && self.balance
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |balance| balance.checked_sub(SCALE as u64))
+            .is_ok()
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use shared::{HealthReport, MonitoredProcessor, ProcessorConfig, ProcessorHealth};
+use crate::routing::circuit_breaker::CircuitBreaker;
+use crate::routing::latency::LatencyWindow;
+
+// Weight a new request outcome gets in the observed error rate
+const ERROR_RATE_WEIGHT: f64 = 0.1;
+// Number of latest response times kept to estimate latency percentiles
+const LATENCY_WINDOW_SIZE: usize = 200;
+
+/// One payment processor the proxy forwards to, with what we know about it so far.
+#[derive(Debug)]
+pub struct Upstream {
+    pub name: String,
+    pub base_url: String,
+    // Share of each payment the processor keeps, between 0 and 1
+    pub fee: f64,
+    pub priority: u32,
+    pub weight: u32,
+    health: ProcessorHealth,
+    // f64 bits of the moving average of failed requests
+    error_rate: AtomicU64,
+    breaker: Option<CircuitBreaker>,
+    latency: LatencyWindow,
+}
+
+impl Upstream {
+    pub fn new(config: ProcessorConfig, breaker: Option<CircuitBreaker>) -> Self {
+        Self {
+            health: ProcessorHealth::new(config.name.clone(), format!("{}{}", config.url, config.health_path)),
+            name: config.name,
+            base_url: config.url,
+            fee: config.fee,
+            priority: config.priority,
+            weight: config.weight,
+            error_rate: AtomicU64::new(0f64.to_bits()),
+            breaker,
+            latency: LatencyWindow::new(LATENCY_WINDOW_SIZE),
+        }
+    }
+
+    /// Lets a request through when the circuit breaker, if this processor has one, allows it.
+    /// The outcome of the request is recorded through the returned admission.
+    pub fn admit(&self) -> Option<Admission<'_>> {
+        self.breaker.as_ref()
+            .is_none_or(|breaker| breaker.try_acquire())
+            .then_some(Admission { upstream: self, settled: false })
+    }
+
+    /// State of the circuit breaker, if this processor has one.
+    pub fn circuit_state(&self) -> Option<&'static str> {
+        self.breaker.as_ref().map(CircuitBreaker::state)
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.health.is_failing()
+    }
+
+    /// Not failing by its own account and not shut off by an open circuit breaker.
+    pub fn is_available(&self) -> bool {
+        !self.is_failing() && self.circuit_state() != Some("open")
+    }
+
+    pub fn min_response_time_ms(&self) -> u64 {
+        self.health.min_response_time_ms()
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        f64::from_bits(self.error_rate.load(Ordering::SeqCst))
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        self.latency.record(latency);
+    }
+
+    pub fn latency_percentile(&self, percentile: f64, min_samples: usize) -> Option<Duration> {
+        self.latency.percentile(percentile, min_samples)
+    }
+
+    // Hands back what `admit` took, for a request that was dropped before it was answered
+    fn release(&self) {
+        if let Some(breaker) = &self.breaker {
+            breaker.release();
+        }
+    }
+
+    fn record_success(&self) {
+        self.record_outcome(0.0);
+        if let Some(breaker) = &self.breaker {
+            breaker.record_success();
+        }
+    }
+
+    fn record_failure(&self) {
+        self.record_outcome(1.0);
+        if let Some(breaker) = &self.breaker {
+            breaker.record_failure();
+        }
+    }
+
+    fn record_outcome(&self, outcome: f64) {
+        let _ = self.error_rate.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bits| {
+            let rate = f64::from_bits(bits);
+            Some((rate + ERROR_RATE_WEIGHT * (outcome - rate)).to_bits())
+        });
+    }
+}
+
+impl MonitoredProcessor for Upstream {
+    fn health(&self) -> &ProcessorHealth {
+        &self.health
+    }
+
+    fn apply_health(&self, report: &HealthReport) {
+        self.health.apply(report);
+        // Lets the error rate of a processor that gets no traffic wind back down
+        if !report.failing {
+            self.record_outcome(0.0);
+        }
+    }
+
+    /// How long the circuit breaker stays open, when it opened on failures seen here since
+    /// the last call.
+    fn take_shut_off(&self) -> Option<Duration> {
+        self.breaker.as_ref()
+            .filter(|breaker| breaker.take_opened())
+            .map(CircuitBreaker::cooldown)
+    }
+
+    /// Opens the circuit breaker, if closed, because another replica's opened.
+    fn shut_off_elsewhere(&self) {
+        if let Some(breaker) = &self.breaker {
+            breaker.open();
+        }
+    }
+}
+
+/// A request an upstream let through. Recording its outcome uses it up; dropping it before
+/// that, as happens when the client goes away mid-request, hands a half-open probe back so
+/// the breaker does not keep waiting on an answer that never comes.
+pub struct Admission<'a> {
+    upstream: &'a Upstream,
+    settled: bool,
+}
+
+impl<'a> Admission<'a> {
+    pub fn upstream(&self) -> &'a Upstream {
+        self.upstream
+    }
+
+    pub fn record_success(mut self) {
+        self.settled = true;
+        self.upstream.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.settled = true;
+        self.upstream.record_failure();
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.upstream.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+    use crate::config::Settings;
+
+    fn upstream() -> Upstream {
+        let settings = Settings::for_tests(json!({
+            "circuit_breaker_window_size": 1,
+            "circuit_breaker_min_requests": 1,
+            "circuit_breaker_cooldown_ms": 0,
+            "circuit_breaker_half_open_probes": 1,
+        }));
+        let config = ProcessorConfig { fee: 0.05, ..ProcessorConfig::new("default", "http://default".to_string(), 0) };
+
+        Upstream::new(config.clone(), Some(CircuitBreaker::new(config.name, &settings)))
+    }
+
+    #[test]
+    fn a_dropped_admission_hands_its_probe_back() {
+        let upstream = upstream();
+        upstream.admit().unwrap().record_failure();
+        assert_eq!(upstream.circuit_state(), Some("open"));
+
+        // The cooldown is over right away, and the single probe is taken
+        let probe = upstream.admit().unwrap();
+        assert!(upstream.admit().is_none());
+
+        drop(probe);
+        assert!(upstream.admit().is_some());
+    }
+
+    #[test]
+    fn a_settled_admission_keeps_its_outcome() {
+        let upstream = upstream();
+        upstream.admit().unwrap().record_failure();
+
+        upstream.admit().unwrap().record_success();
+        assert_eq!(upstream.circuit_state(), Some("closed"));
+    }
+}
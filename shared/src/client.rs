@@ -0,0 +1,69 @@
+use std::time::Duration;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+/// How core and the proxy call the payment processors. Both flatten it into their own
+/// settings, so its fields are read from `APP_PROCESSOR_*` like the others.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientSettings {
+    #[serde(default = "default_processor_connect_timeout_ms")]
+    pub processor_connect_timeout_ms: u64,
+    #[serde(default = "default_processor_request_timeout_ms")]
+    pub processor_request_timeout_ms: u64,
+    #[serde(default = "default_processor_pool_idle_timeout_ms")]
+    pub processor_pool_idle_timeout_ms: u64,
+    #[serde(default = "default_processor_pool_max_idle_per_host")]
+    pub processor_pool_max_idle_per_host: usize,
+    #[serde(default = "default_processor_tcp_keepalive_ms")]
+    pub processor_tcp_keepalive_ms: u64,
+    #[serde(default)]
+    pub processor_http2_prior_knowledge: bool,
+}
+
+fn default_processor_connect_timeout_ms() -> u64 {
+    1_000
+}
+
+// Covers the whole exchange, from connecting until the response body is read
+fn default_processor_request_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_processor_pool_idle_timeout_ms() -> u64 {
+    90_000
+}
+
+fn default_processor_pool_max_idle_per_host() -> usize {
+    32
+}
+
+// Zero turns TCP keepalive off
+fn default_processor_tcp_keepalive_ms() -> u64 {
+    60_000
+}
+
+impl ClientSettings {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.processor_request_timeout_ms)
+    }
+}
+
+/// HTTP client for talking to the payment processors. Fails when the settings describe a
+/// client that cannot be built, which the services report at startup.
+pub fn build_client(settings: &ClientSettings) -> reqwest::Result<Client> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_millis(settings.processor_connect_timeout_ms))
+        .timeout(settings.request_timeout())
+        .pool_idle_timeout(Duration::from_millis(settings.processor_pool_idle_timeout_ms))
+        .pool_max_idle_per_host(settings.processor_pool_max_idle_per_host);
+
+    if settings.processor_tcp_keepalive_ms > 0 {
+        builder = builder.tcp_keepalive(Duration::from_millis(settings.processor_tcp_keepalive_ms));
+    }
+
+    if settings.processor_http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    builder.build()
+}
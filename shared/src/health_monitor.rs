@@ -0,0 +1,316 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use redis::{AsyncCommands, Client, Script};
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+// Keys shared by core and the proxy, so every replica of either service sees the same view.
+// Per processor keys sit under their own prefix, so no processor name can land on the lease
+const LEASE_KEY: &str = "processor_health:lease";
+const REPORT_KEY_PREFIX: &str = "processor_health:report:";
+const BREAKER_KEY_PREFIX: &str = "processor_breaker:";
+
+// Extends the lease (KEYS[1]) only while this replica (ARGV[1]) still holds it
+const RENEW_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+/// What a processor's health endpoint last said, as published for every replica.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct HealthReport {
+    pub failing: bool,
+    #[serde(rename = "minResponseTime")]
+    pub min_response_time: u64,
+    // Milliseconds since the epoch
+    #[serde(rename = "checkedAt")]
+    pub checked_at: i64,
+}
+
+impl HealthReport {
+    /// A report checked just now.
+    pub fn new(failing: bool, min_response_time: u64) -> Self {
+        Self {
+            failing,
+            min_response_time,
+            checked_at: now_millis(),
+        }
+    }
+}
+
+/// Makes one replica at a time poll the processors' health endpoints, which only answer
+/// one call every five seconds. The replica holding the Redis lease publishes what it sees
+/// and the others read it. Replicas also tell each other when a processor's circuit breaker
+/// opens. Without Redis, or while it fails, every replica polls on its own.
+#[derive(Clone)]
+pub struct HealthMonitor {
+    client: Option<Client>,
+    // Opened on first use and kept; it reconnects on its own when Redis goes away
+    connection: Arc<OnceCell<ConnectionManager>>,
+    replica_name: String,
+    interval: Duration,
+    lease_ttl: Duration,
+}
+
+impl HealthMonitor {
+    /// `request_timeout` and `processors` bound how long one round of polling can take,
+    /// which the lease has to outlast so the holder does not lose it halfway through.
+    pub fn new(
+        redis_url: Option<&str>,
+        replica_name: String,
+        interval: Duration,
+        request_timeout: Duration,
+        processors: usize,
+    ) -> Self {
+        let client = redis_url.and_then(|url| match Client::open(url) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                warn!("Invalid Redis URL for shared processor health, polling locally: {}", e);
+                None
+            }
+        });
+
+        Self {
+            client,
+            connection: Arc::new(OnceCell::new()),
+            replica_name,
+            interval,
+            lease_ttl: interval + request_timeout * processors as u32,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn connection(&self) -> Option<ConnectionManager> {
+        let client = self.client.as_ref()?;
+
+        let connection = self.connection
+            .get_or_try_init(|| ConnectionManager::new(client.clone()))
+            .await;
+
+        match connection {
+            Ok(conn) => Some(conn.clone()),
+            Err(e) => {
+                warn!("Failed to reach Redis for shared processor health, polling locally: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Whether this replica should poll the processors itself, taking or renewing the lease
+    /// when Redis is in use. The lease outlives an interval plus a round of polling where
+    /// every processor times out, so a replica that stops renewing it hands over to another
+    /// within that time.
+    pub async fn is_leader(&self) -> bool {
+        let Some(mut conn) = self.connection().await else {
+            return true;
+        };
+        let ttl_ms = self.lease_ttl.as_millis() as u64;
+
+        let renewed: redis::RedisResult<i64> = Script::new(RENEW_SCRIPT)
+            .key(LEASE_KEY)
+            .arg(&self.replica_name)
+            .arg(ttl_ms)
+            .invoke_async(&mut conn)
+            .await;
+
+        match renewed {
+            Ok(1) => return true,
+            Ok(_) => {},
+            Err(e) => {
+                warn!("Failed to renew processor health lease, polling locally: {}", e);
+                return true;
+            }
+        }
+
+        let acquired: redis::RedisResult<Option<String>> = redis::cmd("SET")
+            .arg(LEASE_KEY)
+            .arg(&self.replica_name)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl_ms)
+            .query_async(&mut conn)
+            .await;
+
+        match acquired {
+            Ok(Some(_)) => {
+                info!("{} now polls processor health for every replica", self.replica_name);
+                true
+            },
+            Ok(None) => false,
+            Err(e) => {
+                warn!("Failed to take processor health lease, polling locally: {}", e);
+                true
+            }
+        }
+    }
+
+    pub async fn publish(&self, name: &str, report: &HealthReport) {
+        let Some(mut conn) = self.connection().await else {
+            return;
+        };
+        let Ok(serialized) = serde_json::to_string(report) else {
+            return;
+        };
+
+        if let Err(e) = conn.set::<_, _, ()>(report_key(name), serialized).await {
+            warn!("Failed to publish {} processor health: {}", name, e);
+        }
+    }
+
+    /// The latest published report for a processor, unless the replica polling has been
+    /// silent for too long for it to still be trusted. A lease holder that went away is
+    /// replaced within two lease lifetimes.
+    pub async fn read(&self, name: &str) -> Option<HealthReport> {
+        let mut conn = self.connection().await?;
+
+        let serialized: Option<String> = match conn.get(report_key(name)).await {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                warn!("Failed to read {} processor health: {}", name, e);
+                return None;
+            }
+        };
+
+        let report = serde_json::from_str::<HealthReport>(&serialized?).ok()?;
+        let max_age_ms = self.lease_ttl.as_millis() as i64 * 2;
+
+        (now_millis() - report.checked_at <= max_age_ms).then_some(report)
+    }
+
+    /// Tells the other replicas this one stopped sending to a processor, for as long as
+    /// they should stop too.
+    pub async fn publish_breaker_open(&self, name: &str, open_for: Duration) {
+        let Some(mut conn) = self.connection().await else {
+            return;
+        };
+
+        let published: redis::RedisResult<()> = redis::cmd("SET")
+            .arg(breaker_key(name))
+            .arg(&self.replica_name)
+            .arg("PX")
+            .arg(open_for.as_millis().max(1) as u64)
+            .query_async(&mut conn)
+            .await;
+
+        if let Err(e) = published {
+            warn!("Failed to publish {} circuit breaker: {}", name, e);
+        }
+    }
+
+    /// Whether another replica stopped sending to a processor and it has not been long
+    /// enough to try again. What this replica published itself is left out.
+    pub async fn is_breaker_open_elsewhere(&self, name: &str) -> bool {
+        let Some(mut conn) = self.connection().await else {
+            return false;
+        };
+
+        match conn.get::<_, Option<String>>(breaker_key(name)).await {
+            Ok(opened_by) => opened_by.is_some_and(|opened_by| opened_by != self.replica_name),
+            Err(e) => {
+                warn!("Failed to read {} circuit breaker: {}", name, e);
+                false
+            }
+        }
+    }
+}
+
+fn report_key(name: &str) -> String {
+    format!("{}{}", REPORT_KEY_PREFIX, name)
+}
+
+fn breaker_key(name: &str) -> String {
+    format!("{}{}", BREAKER_KEY_PREFIX, name)
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REDIS_URL: &str = "redis://127.0.0.1:6379";
+
+    // The lease key is the same for every replica, so the tests using it take turns
+    static LEASE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    // A replica whose lease lasts `lease_ttl`
+    fn replica(name: &str, lease_ttl: Duration) -> HealthMonitor {
+        HealthMonitor::new(Some(REDIS_URL), name.to_string(), lease_ttl, Duration::ZERO, 1)
+    }
+
+    async fn clear_lease(monitor: &HealthMonitor) {
+        let mut conn = monitor.connection().await.expect("Redis on localhost");
+        conn.del::<_, ()>(LEASE_KEY).await.unwrap();
+    }
+
+    #[test]
+    fn processor_keys_never_name_the_lease() {
+        for name in ["lease", "", "default"] {
+            assert_ne!(report_key(name), LEASE_KEY);
+            assert_ne!(breaker_key(name), LEASE_KEY);
+        }
+    }
+
+    #[tokio::test]
+    async fn without_redis_every_replica_polls() {
+        let monitor = HealthMonitor::new(None, "a".to_string(), Duration::from_secs(1), Duration::ZERO, 1);
+
+        assert!(monitor.is_leader().await);
+        assert!(monitor.read("default").await.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis on localhost"]
+    async fn the_first_replica_takes_the_lease_and_renews_it() {
+        let _turn = LEASE.lock().await;
+        let (a, b) = (replica("a", Duration::from_millis(300)), replica("b", Duration::from_millis(300)));
+        clear_lease(&a).await;
+
+        assert!(a.is_leader().await);
+        assert!(!b.is_leader().await);
+
+        // Renewed halfway, so it outlasts its first lifetime
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(a.is_leader().await);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!b.is_leader().await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis on localhost"]
+    async fn another_replica_takes_over_a_lease_that_ran_out() {
+        let _turn = LEASE.lock().await;
+        let (a, b) = (replica("a", Duration::from_millis(100)), replica("b", Duration::from_millis(100)));
+        clear_lease(&a).await;
+
+        assert!(a.is_leader().await);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(b.is_leader().await);
+        // Renewing fails for the replica that let it run out
+        assert!(!a.is_leader().await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis on localhost"]
+    async fn a_processor_named_lease_leaves_the_lease_alone() {
+        let _turn = LEASE.lock().await;
+        let (a, b) = (replica("a", Duration::from_secs(5)), replica("b", Duration::from_secs(5)));
+        clear_lease(&a).await;
+
+        assert!(a.is_leader().await);
+        a.publish("lease", &HealthReport::new(true, 10)).await;
+
+        assert!(!b.is_leader().await);
+        assert!(b.read("lease").await.is_some_and(|report| report.failing));
+    }
+}
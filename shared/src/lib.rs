@@ -0,0 +1,9 @@
+mod client;
+mod health_monitor;
+mod processor_config;
+mod processor_health;
+
+pub use client::{ClientSettings, build_client};
+pub use health_monitor::{HealthMonitor, HealthReport};
+pub use processor_config::{ProcessorConfig, parse_processors};
+pub use processor_health::{MonitoredProcessor, ProcessorHealth};
@@ -0,0 +1,104 @@
+use config::ConfigError;
+use serde::{Deserialize, Serialize};
+
+/// One processor payments can go to, as `APP_PAYMENT_PROCESSORS` lists it for core and the
+/// proxy alike. Weight and fee only matter to the proxy's routing.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProcessorConfig {
+    pub name: String,
+    pub url: String,
+    // Lower is tried first, unless the proxy's routing policy has a reason to choose
+    #[serde(default)]
+    pub priority: u32,
+    // Share of the traffic among processors the proxy's policy ranks the same
+    #[serde(default = "default_processor_weight")]
+    pub weight: u32,
+    // Share of each payment the processor keeps, between 0 and 1
+    #[serde(default)]
+    pub fee: f64,
+    #[serde(rename = "healthPath")]
+    #[serde(default = "default_processor_health_path")]
+    pub health_path: String,
+}
+
+fn default_processor_weight() -> u32 {
+    1
+}
+
+fn default_processor_health_path() -> String {
+    "/payments/service-health".to_string()
+}
+
+impl ProcessorConfig {
+    /// A processor with every optional field left at its default.
+    pub fn new(name: &str, url: String, priority: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            url,
+            priority,
+            weight: default_processor_weight(),
+            fee: 0.0,
+            health_path: default_processor_health_path(),
+        }
+    }
+}
+
+/// The processors in the JSON list `payment_processors`, or the ones `unlisted` returns
+/// when it is unset, sorted by priority.
+pub fn parse_processors(
+    payment_processors: Option<&str>,
+    unlisted: impl FnOnce() -> Result<Vec<ProcessorConfig>, ConfigError>,
+) -> Result<Vec<ProcessorConfig>, ConfigError> {
+    let mut processors = match payment_processors {
+        Some(payment_processors) => serde_json::from_str::<Vec<ProcessorConfig>>(payment_processors)
+            .map_err(|e| ConfigError::Message(format!("APP_PAYMENT_PROCESSORS must be a JSON list of processors: {}", e)))?,
+        None => unlisted()?,
+    };
+
+    if processors.is_empty() {
+        return Err(ConfigError::Message("APP_PAYMENT_PROCESSORS must list at least one processor".to_string()));
+    }
+    // Core's summary reports its window next to one field per processor
+    if processors.iter().any(|processor| processor.name == "window") {
+        return Err(ConfigError::Message("APP_PAYMENT_PROCESSORS cannot name a processor 'window'".to_string()));
+    }
+    processors.sort_by_key(|processor| processor.priority);
+
+    Ok(processors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(payment_processors: &str) -> Result<Vec<ProcessorConfig>, ConfigError> {
+        parse_processors(Some(payment_processors), || unreachable!())
+    }
+
+    #[test]
+    fn lists_the_processors_by_priority() {
+        let processors = parse(r#"[{"name": "b", "url": "http://b", "priority": 1}, {"name": "a", "url": "http://a"}]"#).unwrap();
+
+        let names = processors.iter().map(|processor| processor.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(processors[0].weight, 1);
+        assert_eq!(processors[0].health_path, "/payments/service-health");
+    }
+
+    #[test]
+    fn falls_back_to_the_unlisted_processors() {
+        let processors = parse_processors(None, || Ok(vec![ProcessorConfig::new("default", "http://default".to_string(), 0)])).unwrap();
+
+        assert_eq!(processors[0].name, "default");
+    }
+
+    #[test]
+    fn rejects_an_empty_list() {
+        assert!(parse("[]").is_err());
+    }
+
+    #[test]
+    fn rejects_a_processor_named_like_the_summary_window() {
+        assert!(parse(r#"[{"name": "default", "url": "http://default"}, {"name": "window", "url": "http://window"}]"#).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use reqwest::Client;
+use serde::Deserialize;
+use tracing::{info, warn};
+use crate::health_monitor::{HealthMonitor, HealthReport};
+
+#[derive(Deserialize)]
+struct ServiceHealth {
+    failing: bool,
+    #[serde(rename = "minResponseTime")]
+    min_response_time: u64,
+}
+
+/// What a processor last said about its health.
+#[derive(Debug)]
+pub struct ProcessorHealth {
+    name: String,
+    health_url: String,
+    failing: AtomicBool,
+    min_response_time_ms: AtomicU64,
+}
+
+impl ProcessorHealth {
+    pub fn new(name: String, health_url: String) -> Self {
+        Self {
+            name,
+            health_url,
+            failing: AtomicBool::new(false),
+            min_response_time_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.failing.load(Ordering::SeqCst)
+    }
+
+    pub fn min_response_time_ms(&self) -> u64 {
+        self.min_response_time_ms.load(Ordering::SeqCst)
+    }
+
+    /// Marks the processor as failing until the next report. Returns `false` when it
+    /// already was.
+    pub fn mark_failing(&self) -> bool {
+        !self.failing.swap(true, Ordering::SeqCst)
+    }
+
+    /// Asks the processor for its health. `None` means it rate limited us and what we knew
+    /// still stands; a processor that cannot answer is reported as failing.
+    pub async fn poll(&self, client: &Client) -> Option<HealthReport> {
+        let health = match client.get(&self.health_url).send().await {
+            Ok(res) if res.status().is_success() => res.json::<ServiceHealth>().await.ok(),
+            Ok(res) if res.status().as_u16() == 429 => return None,
+            Ok(_) | Err(_) => None,
+        };
+
+        let report = match health {
+            Some(health) => HealthReport::new(health.failing, health.min_response_time),
+            None => {
+                warn!("{} processor health check failed", self.name);
+                HealthReport::new(true, self.min_response_time_ms())
+            }
+        };
+
+        Some(report)
+    }
+
+    pub fn apply(&self, report: &HealthReport) {
+        if self.failing.swap(report.failing, Ordering::SeqCst) != report.failing {
+            info!("{} processor is now {}", self.name, if report.failing { "failing" } else { "healthy" });
+        }
+        self.min_response_time_ms.store(report.min_response_time, Ordering::SeqCst);
+    }
+}
+
+/// A processor the health checks keep up to date, as core and the proxy each see it.
+pub trait MonitoredProcessor: Send + Sync + 'static {
+    fn health(&self) -> &ProcessorHealth;
+
+    /// Takes in a report, whether this replica polled it or read what another published.
+    fn apply_health(&self, report: &HealthReport) {
+        self.health().apply(report);
+    }
+
+    /// How long the other replicas should stop sending to the processor, when this one
+    /// stopped since the last call.
+    fn take_shut_off(&self) -> Option<Duration>;
+
+    /// Another replica stopped sending to the processor and it has not been long enough
+    /// to try again.
+    fn shut_off_elsewhere(&self);
+}
+
+impl HealthMonitor {
+    /// Spawns the task that refreshes the processors' health every interval, polling them
+    /// when this replica holds the health lease and reading what the holder published
+    /// otherwise. Processors one replica stopped sending to are then shut off on the others.
+    pub fn start<P: MonitoredProcessor>(self, client: Client, processors: Arc<Vec<Arc<P>>>) {
+        info!("Starting processor health checks every {:?}", self.interval());
+
+        tokio::spawn(async move {
+            loop {
+                if self.is_leader().await {
+                    for processor in processors.iter() {
+                        if let Some(report) = processor.health().poll(&client).await {
+                            processor.apply_health(&report);
+                            self.publish(processor.health().name(), &report).await;
+                        }
+                    }
+                } else {
+                    for processor in processors.iter() {
+                        if let Some(report) = self.read(processor.health().name()).await {
+                            processor.apply_health(&report);
+                        }
+                    }
+                }
+
+                for processor in processors.iter() {
+                    let name = processor.health().name();
+                    if let Some(open_for) = processor.take_shut_off() {
+                        self.publish_breaker_open(name, open_for).await;
+                    } else if self.is_breaker_open_elsewhere(name).await {
+                        processor.shut_off_elsewhere();
+                    }
+                }
+
+                tokio::time::sleep(self.interval()).await;
+            }
+        });
+    }
+}